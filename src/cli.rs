@@ -1,7 +1,9 @@
-use std::fs;
-use clap::{ App, SubCommand, Arg };
+use std::fs::{ self, OpenOptions };
+use std::io::{ self, Write };
+use clap::{ App, SubCommand, Arg, ArgMatches };
 use serde_json;
-use crate::expect_pretty;
+use openssl::pkcs12::Pkcs12;
+use crate::{ expect_pretty, tls };
 use crate::url_tree::{ self, UrlNode, ServerSettings, Config };
 
 pub fn run_app() {
@@ -42,6 +44,69 @@ pub fn run_app() {
                 if this is missing, the url trees for all domains will be written")
             )
         )
+        .subcommand(SubCommand::with_name("cert")
+            .about("Generates or inspects the certificates of the server")
+            .subcommand(SubCommand::with_name("generate")
+                .about("Creates a self-signed certificate and writes it to the files in the server settings")
+                .arg(Arg::with_name("domain")
+                    .short("d")
+                    .long("domain")
+                    .value_name("DOMAIN")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .help("Specifies a domain the certificate is valid for, this can be given multiple times. \
+                    If this is missing, all configured domains without a certificate of their own are used")
+                )
+                .arg(Arg::with_name("key-type")
+                    .short("k")
+                    .long("key-type")
+                    .value_name("TYPE")
+                    .takes_value(true)
+                    .possible_values(&["rsa2048", "rsa4096", "ecdsa-p256", "ecdsa-p384"])
+                    .default_value("rsa4096")
+                    .help("Specifies the type of the private key")
+                )
+                .arg(Arg::with_name("days")
+                    .long("days")
+                    .value_name("DAYS")
+                    .takes_value(true)
+                    .default_value("365")
+                    .help("Specifies the amount of days the certificate is valid for")
+                )
+                .arg(Arg::with_name("format")
+                    .short("f")
+                    .long("format")
+                    .value_name("FORMAT")
+                    .takes_value(true)
+                    .possible_values(&["pfx", "pem"])
+                    .help("Specifies the format of the written files, if this is missing, pem is used \
+                    if \"tls_cert\" or \"tls_key\" is set in the server settings and pfx otherwise")
+                )
+                .arg(Arg::with_name("out")
+                    .short("o")
+                    .long("out")
+                    .value_name("FILE")
+                    .takes_value(true)
+                    .help("Specifies the relative path of the pfx file or pem certificate, \
+                    if this is missing, \"tls_profile\" or \"tls_cert\" from the server settings is used")
+                )
+                .arg(Arg::with_name("key-out")
+                    .long("key-out")
+                    .value_name("FILE")
+                    .takes_value(true)
+                    .help("Specifies the relative path of the pem private key, \
+                    if this is missing, \"tls_key\" from the server settings is used")
+                )
+                .arg(Arg::with_name("force")
+                    .long("force")
+                    .help("Overwrites existing files")
+                )
+            )
+            .subcommand(SubCommand::with_name("show")
+                .about("Prints the fingerprint, names and expiry of the certificates in the server settings")
+            )
+        )
         .get_matches();
 
     if let Some(matches) = matches.subcommand_matches("tree") {
@@ -55,6 +120,18 @@ pub fn run_app() {
         
         create_template(path);
     }
+    else if let Some(matches) = matches.subcommand_matches("cert") {
+        if let Some(matches) = matches.subcommand_matches("generate") {
+            generate_certificate(matches);
+        }
+        else if let Some(_) = matches.subcommand_matches("show") {
+            let settings = url_tree::get_url_tree().settings;
+            print!("{}", tls::get_certificate_info(&settings));
+        }
+        else {
+            eprintln!("{}", matches.usage());
+        }
+    }
     else if let Some(_) = matches.subcommand_matches("start") {
         crate::start_server();
     }
@@ -100,6 +177,108 @@ fn write_tree(domain: Option<&str>, outfile: Option<&str>) {
     }
 }
 
+fn generate_certificate(matches: &ArgMatches) {
+    let tree = url_tree::get_url_tree();
+    let settings = &tree.settings;
+
+    // Domains with a certificate of their own do not need to be covered
+    let domains: Vec<String> = match matches.values_of("domain") {
+        Some(values) => values.map(String::from).collect(),
+        None => tree.roots.iter()
+            .map(|root| root.name.clone())
+            .filter(|name| !settings.certificates.iter().any(|cert| cert.domain.eq_ignore_ascii_case(name)))
+            .collect()
+    };
+    if domains.is_empty() {
+        eprintln!("Error: There are no domains to create a certificate for");
+        return;
+    }
+
+    let days: u32 = match matches.value_of("days").unwrap().parse() {
+        Ok(val) if val > 0 => val,
+        _ => {
+            eprintln!("Error: The amount of days must be a positive number");
+            return;
+        }
+    };
+    let use_pem = match matches.value_of("format") {
+        Some(format) => format == "pem",
+        None => settings.tls_cert.is_some() || settings.tls_key.is_some()
+    };
+    let force = matches.is_present("force");
+
+    let pkey = expect_pretty(tls::generate_key(matches.value_of("key-type").unwrap()), "Error: Failed to generate private key");
+    let cert = expect_pretty(tls::create_self_signed(&pkey, &domains, days), "Error: Failed to create certificate");
+
+    if use_pem {
+        let cert_path = matches.value_of("out").or(settings.tls_cert.as_deref());
+        let key_path = matches.value_of("key-out").or(settings.tls_key.as_deref());
+        let (cert_path, key_path) = match (cert_path, key_path) {
+            (Some(cert_path), Some(key_path)) => (cert_path, key_path),
+            _ => {
+                eprintln!("Error: Set \"tls_cert\" and \"tls_key\" in the server settings or use --out and --key-out");
+                return;
+            }
+        };
+
+        let cert_pem = expect_pretty(cert.to_pem(), "Error: Failed to encode certificate");
+        let key_pem = expect_pretty(pkey.private_key_to_pem_pkcs8(), "Error: Failed to encode private key");
+        if !write_new_file(cert_path, &cert_pem, force, false) || !write_new_file(key_path, &key_pem, force, true) {
+            return;
+        }
+        println!("Created certificate \"{}\" and private key \"{}\"", cert_path, key_path);
+    }
+    else {
+        let profile_path = matches.value_of("out").unwrap_or(&settings.tls_profile);
+        let pkcs12 = expect_pretty(
+            Pkcs12::builder().build(&settings.profile_password, &domains[0], &pkey, &cert),
+            "Error: Failed to create pfx file"
+        );
+        let pfx_data = expect_pretty(pkcs12.to_der(), "Error: Failed to encode pfx file");
+        if !write_new_file(profile_path, &pfx_data, force, true) {
+            return;
+        }
+        println!("Created profile \"{}\" (protected with \"profile_password\")", profile_path);
+    }
+
+    println!("Domains: {}", domains.join(", "));
+    println!("Fingerprint: {}", tls::get_fingerprint(&cert));
+}
+
+// Writes the file if it does not exist yet (or force is set), private files are only readable by the owner
+fn write_new_file(path: &str, data: &[u8], force: bool, private: bool) -> bool {
+    let mut options = OpenOptions::new();
+    options.write(true);
+    if force {
+        options.create(true).truncate(true);
+    }
+    else {
+        options.create_new(true);
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        if private {
+            options.mode(0o600);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = private;
+
+    let result = options.open(path).and_then(|mut file| file.write_all(data));
+    match result {
+        Ok(_) => true,
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+            eprintln!("Error: The file \"{}\" already exists, use --force to overwrite it", path);
+            false
+        },
+        Err(err) => {
+            eprintln!("Error: Failed to write \"{}\". {}", path, err);
+            false
+        }
+    }
+}
+
 fn create_template(path: &str) {
     let dir_fail = "Error: failed to create directory";
 
@@ -135,8 +314,15 @@ fn create_template(path: &str) {
         default_whitelist: false,
         dynamic: Vec::new(),
         link: Vec::new(),
+        redirect: Vec::new(),
+        gone: Vec::new(),
+        proxy: Vec::new(),
+        upload: Vec::new(),
+        access: Vec::new(),
+        certificate_groups: Vec::new(),
         config_files: Vec::new(),
-        default_preload: None
+        default_preload: None,
+        requires_certificate: None
     };
     let config_display = expect_pretty(serde_json::to_string_pretty(&config), "Failed to serialize");
     expect_pretty(
@@ -21,6 +21,12 @@ mod server;
 mod url_tree;
 mod protocol;
 mod cli;
+mod rate_limit;
+mod proxy;
+mod worker_pool;
+mod shutdown;
+mod signals;
+mod tls;
 
 const TEMP_DIR: &str = "temp";
 const LOG_FILE: &str = "log.txt";
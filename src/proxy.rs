@@ -0,0 +1,150 @@
+use std::net::{ TcpStream, ToSocketAddrs, IpAddr };
+use std::io::{ self, BufRead, BufReader, Read, Write };
+use std::fs;
+use std::str;
+use std::time::Duration;
+use std::fmt::Display;
+use openssl::ssl::{ SslConnector, SslMethod, SslStream, SslVerifyMode };
+use openssl::pkcs12::Pkcs12;
+use crate::{ Result, ServerError };
+use crate::url_tree::{ ProxyObject, Path };
+use crate::protocol::{ self, Request, StatusCode };
+
+// Forwards the request to the upstream server and streams the response back to the client.
+// An error is only returned if nothing has been written to the client yet
+pub fn forward<W: Write>(client: &mut W, proxy: &ProxyObject, request: &Request) -> Result<()> {
+    let mut upstream = connect(proxy)?;
+
+    // Send the request line
+    let request_line = format!("{}\r\n", get_upstream_url(proxy, request));
+    if let Err(err) = upstream.write_all(request_line.as_bytes()) {
+        return proxy_error(&err);
+    }
+
+    // Read and validate the response header
+    let mut reader = BufReader::new(upstream);
+    let mut header = Vec::new();
+    if let Err(err) = reader.by_ref().take(protocol::MAX_HEADER_SIZE as u64).read_until(b'\n', &mut header) {
+        return proxy_error(&err);
+    }
+    validate_header(&header)?;
+
+    // Stream the response to the client
+    if client.write_all(&header).is_err() {
+        return Ok(());
+    }
+    match io::copy(&mut reader, client) {
+        Ok(_) => (),
+        Err(_) => () // Errors can no longer be sent to the client at this point
+    };
+
+    Ok(())
+}
+
+fn connect(proxy: &ProxyObject) -> Result<SslStream<TcpStream>> {
+    let timeout = Duration::from_secs(proxy.timeout.unwrap()); // Timeout is always set at this point
+    let host = proxy.host.trim_start_matches('[').trim_end_matches(']');
+    let port = proxy.port.unwrap_or(protocol::GEMINI_PORT);
+
+    // Open the connection
+    let addr = match (host, port).to_socket_addrs().map(|mut addrs| addrs.next()) {
+        Ok(Some(val)) => val,
+        Ok(None) => return proxy_error(&format!("No address found for {}", host)),
+        Err(err) => return proxy_error(&err)
+    };
+    let stream = match TcpStream::connect_timeout(&addr, timeout) {
+        Ok(val) => val,
+        Err(err) => return proxy_error(&err)
+    };
+    if let Err(err) = stream.set_read_timeout(Some(timeout)).and(stream.set_write_timeout(Some(timeout))) {
+        return proxy_error(&err);
+    }
+
+    // Gemini servers generally use self-signed certificates, so they are not verified
+    let mut connector = match SslConnector::builder(SslMethod::tls()) {
+        Ok(val) => val,
+        Err(err) => return proxy_error(&err)
+    };
+    connector.set_verify(SslVerifyMode::NONE);
+
+    // Present the configured identity to the upstream server
+    if let Some(profile) = &proxy.tls_profile {
+        let password = proxy.profile_password.as_deref().unwrap_or("");
+        let identity = fs::read(profile)
+            .map_err(|err| err.to_string())
+            .and_then(|data| Pkcs12::from_der(&data).map_err(|err| err.to_string()))
+            .and_then(|pkcs12| pkcs12.parse(password).map_err(|err| err.to_string()));
+        let identity = match identity {
+            Ok(val) => val,
+            Err(err) => return proxy_error(&format!("Failed to load the proxy certificate. {}", err))
+        };
+
+        if let Err(err) = connector.set_certificate(&identity.cert).and(connector.set_private_key(&identity.pkey)) {
+            return proxy_error(&err);
+        }
+    }
+
+    let config = match connector.build().configure() {
+        Ok(val) => val,
+        Err(err) => return proxy_error(&err)
+    };
+    let is_ip = host.parse::<IpAddr>().is_ok();
+    match config.use_server_name_indication(!is_ip).verify_hostname(false).connect(host, stream) {
+        Ok(val) => Ok(val),
+        Err(err) => proxy_error(&err)
+    }
+}
+
+fn get_upstream_url(proxy: &ProxyObject, request: &Request) -> String {
+    // Replace the link path of the request with the upstream path
+    let link_depth = Path::from_str(&proxy.link_path).depth();
+    let rest = Path::from_str(&request.path).skip_components(link_depth);
+    let mut components = match &proxy.path {
+        Some(val) => Path::from_str(val).components,
+        None => Vec::new()
+    };
+    components.extend(rest.components);
+
+    let mut url = format!("gemini://{}", &proxy.host);
+    if let Some(port) = proxy.port {
+        if port != protocol::GEMINI_PORT {
+            url += &format!(":{}", port);
+        }
+    }
+    url.push('/');
+    url += &protocol::encode_path(&components.join("/"));
+    if request.path.ends_with('/') && !components.is_empty() { // Keep trailing slash of directories
+        url.push('/');
+    }
+    if let Some(query) = &request.raw_query {
+        url.push('?');
+        url += query;
+    }
+
+    url
+}
+
+fn validate_header(header: &[u8]) -> Result<()> {
+    let invalid_header = || proxy_error(&"The upstream server sent an invalid header");
+
+    let header = match str::from_utf8(header) {
+        Ok(val) => val,
+        Err(_) => return invalid_header()
+    };
+    let status_line = match header.strip_suffix("\r\n") {
+        Some(val) => val,
+        None => return invalid_header()
+    };
+
+    match protocol::parse_header(status_line) {
+        Some(_) => Ok(()),
+        None => invalid_header()
+    }
+}
+
+fn proxy_error<T>(err: &dyn Display) -> Result<T> {
+    Err(ServerError::new(
+        format!("Error: Failed to proxy request. {}", err),
+        StatusCode::ProxyError
+    ))
+}
@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+use crate::url_tree::RateLimit;
+
+lazy_static! {
+    static ref BUCKETS: Mutex<HashMap<String, Bucket>> = Mutex::new(HashMap::new());
+}
+
+// Token bucket for a single client
+struct Bucket {
+    tokens: f64,
+    last_update: Instant
+}
+
+impl Bucket {
+    fn refill(&mut self, limit: &RateLimit) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_update).as_secs_f64();
+
+        self.tokens = (self.tokens + elapsed * refill_rate(limit)).min(capacity(limit));
+        self.last_update = now;
+    }
+}
+
+// Takes a request from the client's allowance, returns the seconds to wait if none is left
+pub fn check(key: &str, limit: &RateLimit) -> Option<u64> {
+    if limit.requests == 0 || limit.window == 0 { // Limit is disabled
+        return None;
+    }
+
+    let mut buckets = match BUCKETS.lock() {
+        Ok(val) => val,
+        Err(_) => return None // Do not block clients if the lock has been poisoned
+    };
+    let bucket = buckets.entry(key.to_string()).or_insert_with(|| Bucket {
+        tokens: capacity(limit),
+        last_update: Instant::now()
+    });
+
+    bucket.refill(limit);
+    if bucket.tokens >= 1.0 {
+        bucket.tokens -= 1.0;
+        return None;
+    }
+
+    let wait_time = ((1.0 - bucket.tokens) / refill_rate(limit)).ceil() as u64;
+    Some(wait_time.max(1))
+}
+
+// Removes all clients that have their full allowance again
+pub fn remove_idle(limit: &RateLimit) {
+    let mut buckets = match BUCKETS.lock() {
+        Ok(val) => val,
+        Err(_) => return
+    };
+
+    buckets.retain(|_, bucket| {
+        bucket.refill(limit);
+        bucket.tokens < capacity(limit)
+    });
+}
+
+fn refill_rate(limit: &RateLimit) -> f64 {
+    limit.requests as f64 / limit.window as f64
+}
+
+fn capacity(limit: &RateLimit) -> f64 {
+    limit.burst.unwrap_or(limit.requests).max(1) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    fn get_limit(requests: u32, window: u64, burst: Option<u32>) -> RateLimit {
+        RateLimit {
+            requests,
+            window,
+            burst,
+            by_certificate: false
+        }
+    }
+
+    #[test]
+    fn allows_burst() {
+        let limit = get_limit(10, 60, Some(3));
+        for _ in 0..3 {
+            assert_eq!(check("test:burst", &limit), None);
+        }
+        assert!(check("test:burst", &limit).is_some());
+
+        // Burst defaults to the amount of requests
+        let limit = get_limit(5, 60, None);
+        for _ in 0..5 {
+            assert_eq!(check("test:default_burst", &limit), None);
+        }
+        assert!(check("test:default_burst", &limit).is_some());
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let limit = get_limit(10, 1, Some(1)); // One request every 100 milliseconds
+        assert_eq!(check("test:refill", &limit), None);
+        assert!(check("test:refill", &limit).is_some());
+
+        thread::sleep(Duration::from_millis(150));
+        assert_eq!(check("test:refill", &limit), None);
+        assert!(check("test:refill", &limit).is_some());
+    }
+
+    #[test]
+    fn returns_wait_time() {
+        let limit = get_limit(1, 60, Some(1));
+        assert_eq!(check("test:wait", &limit), None);
+        assert_eq!(check("test:wait", &limit), Some(60));
+
+        // Less than a second is rounded up
+        let limit = get_limit(10, 1, Some(1));
+        assert_eq!(check("test:short_wait", &limit), None);
+        assert_eq!(check("test:short_wait", &limit), Some(1));
+    }
+
+    #[test]
+    fn keeps_clients_apart() {
+        let limit = get_limit(1, 60, Some(1));
+        assert_eq!(check("test:first", &limit), None);
+        assert!(check("test:first", &limit).is_some());
+        assert_eq!(check("test:second", &limit), None);
+    }
+
+    #[test]
+    fn ignores_disabled_limit() {
+        for limit in &[get_limit(0, 60, Some(1)), get_limit(1, 0, Some(1))] {
+            for _ in 0..5 {
+                assert_eq!(check("test:disabled", limit), None);
+            }
+        }
+    }
+}
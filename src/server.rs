@@ -1,65 +1,81 @@
 use std::net::{ TcpListener, TcpStream };
-use std::sync::{ Arc, Mutex, MutexGuard };
-use std::fs::{ self, File };
-use std::io::{ Read, Write };
+use std::sync::{ Arc, Mutex, MutexGuard, RwLock };
+use std::sync::mpsc::{ self, Receiver, RecvTimeoutError };
+use std::fs;
+use std::str;
+use std::io::{ self, Read, Write };
 use std::thread;
 use std::collections::{ HashMap, hash_map::DefaultHasher };
-use std::process::{ self, Command };
+use std::process::{ self, ChildStderr, Command, ExitStatus, Stdio };
 use std::fmt::Display;
 use std::time::{ Instant, Duration };
 use std::env;
 use std::error::Error;
 use std::hash::{ Hash, Hasher };
-use openssl::ssl::{ SslAcceptor, SslMethod, SslStream, SslVerifyMode };
-use openssl::pkcs12::Pkcs12;
-use openssl::hash::MessageDigest;
+use std::ops::Deref;
+use openssl::ssl::{ SslAcceptor, SslStream, HandshakeError };
 use openssl::x509::{ X509, X509NameRef };
 use openssl::nid::Nid;
+use openssl::memcmp;
+use openssl::sha::sha256;
 use rand;
-use crate::{ log, expect_pretty, Result, ServerError };
-use crate::url_tree::{ UrlTree, UrlNode, Path, FileType, DynamicObject, FileData };
-use crate::protocol::{ self, Request, Response, StatusCode };
+use crate::{ log, expect_pretty, rate_limit, proxy, shutdown, signals, tls, Result, ServerError };
+use crate::tls::get_fingerprint;
+use crate::worker_pool::WorkerPool;
+use crate::url_tree::{ self, UrlTree, UrlNode, Path, FileType, DynamicObject, CgiMode, FileData, UploadObject, AccessObject };
+use crate::protocol::{ self, Request, Response, StatusCode, Upload };
 
 const BUFFER_SIZE: usize = 2048;
+const MAX_REQUEST_SIZE: usize = 1026; // 1024 byte url and \r\n
 const TEMP_DIR: &str = crate::TEMP_DIR;
 const FILE_MAP_DEL_TIME: u64 = 300; // How often the file id removal thread should be run (seconds)
+const RATE_LIMIT_DEL_TIME: u64 = 60; // How often idle rate limit entries should be removed (seconds)
+const ACCEPT_POLL_TIME: u64 = 50; // How often listeners check for new clients (milliseconds)
+const RELOAD_POLL_TIME: u64 = 1; // How often reload requests are checked (seconds)
+const CERT_CHECK_TIME: u64 = 86400; // How often the expiry of the certificates is checked (seconds)
+const REJECT_TIMEOUT: u64 = 2; // Time allowed for turning away a client while the server is busy (seconds)
+const REJECT_QUEUE_LENGTH: usize = 8; // Amount of clients that can wait to be turned away
+const KILL_GRACE_TIME: u64 = 2; // Time allowed for a timed out process to exit after SIGTERM (seconds)
+const MAX_STDERR_SIZE: u64 = 8192; // Amount of stderr output of a process that is logged (bytes)
+const STDERR_WAIT_TIME: u64 = 500; // Time allowed for reading the rest of stderr after a process has exited (milliseconds)
+const PASSWD_BUFFER_SIZE: usize = 16384; // Buffer for looking up the primary group of a user (bytes)
 
 lazy_static! {
     static ref UNIQUE_FILE_LIST: Mutex<HashMap<u64, Instant>> = Mutex::new(HashMap::new());
     static ref CACHE_DIR: &'static String = &*crate::CACHE_DIR;
 }
 
+// The current url tree, which is swapped out when it is reloaded
+type SharedTree = Arc<RwLock<Arc<UrlTree>>>;
+// The current acceptor, which is swapped out when a certificate is renewed
+type SharedAcceptor = Arc<RwLock<Arc<SslAcceptor>>>;
+
 pub fn run_server(tree: UrlTree) {
     // Create Arc for multithreading
-    let tree = Arc::new(tree);
-
-    // Get certificate
-    let cert_src = &tree.settings.tls_profile;
-    let cert_passwd = &tree.settings.profile_password;
-    let mut pfx_file = expect_pretty(File::open(cert_src), "Critical Error: Failed to open certificate");
-    let mut pfx_data: Vec<u8> = vec![];
-    expect_pretty(pfx_file.read_to_end(&mut pfx_data), "Critical Error: Failed to read certifcate");
-    let pkcs12 = expect_pretty(Pkcs12::from_der(&pfx_data), "Critical Error: Failed to parse pfx file (bad certificate)");
-    let identity = expect_pretty(pkcs12.parse(cert_passwd), "Critical Error: Failed to create identity (incorrect password)");
-
-    // Create Tcp Listeners based on ipv4/6 settings
+    let shared_tree: SharedTree = Arc::new(RwLock::new(Arc::new(tree)));
+    let tree = get_current_tree(&shared_tree);
+
+    // Create Tcp Listeners based on the listen settings
     let mut listeners: Vec<TcpListener> = Vec::new();
-    if tree.settings.ipv6 {
-        let listener = expect_pretty(TcpListener::bind("[::]:1965"), "Critical Error: Failed to bind to address (ipv6)");
-        listeners.push(listener);
-    }
-    if tree.settings.ipv4 {
-        let listener = expect_pretty(TcpListener::bind("0.0.0.0:1965"), "Critical Error: Failed to bind to address (ipv4)");
+    for address in &tree.settings.listen {
+        let error_message = format!("Critical Error: Failed to bind to address {}", address);
+        let listener = expect_pretty(TcpListener::bind(address.as_str()), &error_message);
         listeners.push(listener);
     }
 
-    // Create Tls wrapper for acceptors based on certificate
-    let cert_init_error = "Critical Error: Failed to initialize acceptor";
-    let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls()).expect(cert_init_error);
-    expect_pretty(acceptor.set_certificate(&identity.cert), cert_init_error);
-    expect_pretty(acceptor.set_private_key(&identity.pkey), cert_init_error);
-    acceptor.set_verify_callback(SslVerifyMode::PEER, |_, _| true);
-    let acceptor = Arc::new(acceptor.build());
+    // Warn about expiring certificates (or renew them) before they are loaded
+    tls::check_expiry(&tree.settings);
+
+    // Create Tls wrapper for acceptors based on the certificate(s), this is swapped out when
+    // a certificate is renewed
+    let acceptor = match tls::build_acceptor(&tree.settings) {
+        Ok(val) => val,
+        Err(err) => {
+            eprintln!("Critical Error: {}", err);
+            process::exit(101);
+        }
+    };
+    let acceptor: SharedAcceptor = Arc::new(RwLock::new(Arc::new(acceptor)));
     
     // Spawn thread for removing unused file ids
     thread::spawn(|| {
@@ -69,94 +85,364 @@ pub fn run_server(tree: UrlTree) {
         }
     });
 
+    // Spawn thread for removing idle rate limit entries
+    let shared_tree_copy = shared_tree.clone();
+    thread::spawn(move || {
+        loop {
+            thread::sleep(Duration::from_secs(RATE_LIMIT_DEL_TIME));
+            if let Some(limit) = &get_current_tree(&shared_tree_copy).settings.rate_limit {
+                rate_limit::remove_idle(limit);
+            }
+        }
+    });
+
     // Spawn thread for caching dynamic content
-    let tree_copy = tree.clone();
-    let cache_time = tree.settings.cache_time;
+    let shared_tree_copy = shared_tree.clone();
     thread::spawn(move || {
         loop {
-            cache_files(&tree_copy);
-            thread::sleep(Duration::from_secs(cache_time));
+            let tree = get_current_tree(&shared_tree_copy);
+            cache_files(&tree);
+            thread::sleep(Duration::from_secs(tree.settings.cache_time));
         }
     });
 
-    // Stop if neither ipv6 or ipv4 is enabled
+    // Spawn thread for checking the expiry of the certificates, certificates are only read
+    // when starting, so the settings are not taken from reloaded trees
+    let acceptor_copy = acceptor.clone();
+    let settings = tree.settings.clone();
+    thread::spawn(move || {
+        loop {
+            thread::sleep(Duration::from_secs(CERT_CHECK_TIME));
+            if !tls::check_expiry(&settings) {
+                continue;
+            }
+
+            let new_acceptor = match tls::build_acceptor(&settings) {
+                Ok(val) => Arc::new(val),
+                Err(err) => {
+                    log(&format!("Error: Failed to load the renewed certificates, the old ones stay in service. {}", err));
+                    continue;
+                }
+            };
+            match acceptor_copy.write() {
+                Ok(mut val) => *val = new_acceptor,
+                Err(poisoned) => *poisoned.into_inner() = new_acceptor
+            };
+        }
+    });
+
+    // Spawn thread for reloading the url tree
+    let shared_tree_copy = shared_tree.clone();
+    thread::spawn(move || watch_tree(shared_tree_copy));
+
+    // Stop if there is nothing to listen on
     if listeners.len() == 0 {
-        eprintln!("Critical Error: Either ipv4 or ipv6 must be enabled in the server settings to run the program");
+        eprintln!("Critical Error: At least one address must be set in \"listen\" in the server settings to run the program");
         process::exit(101);
     }
 
     // Log start of server
     log("Info: Started Server");
 
-    // Start server thread(s)
-    if listeners.len() == 1 {
-        let listener0 = listeners.pop().unwrap();
-        handle_server(listener0, acceptor, tree.clone());
+    // Create worker pool shared by all listeners
+    let acceptor_copy = acceptor.clone();
+    let shared_tree_copy = shared_tree.clone();
+    let pool = Arc::new(WorkerPool::new(
+        tree.settings.workers,
+        tree.settings.queue_length,
+        move |(client, active): (TcpStream, shutdown::ClientGuard)| {
+            accept_client(client, active, &get_current_acceptor(&acceptor_copy), get_current_tree(&shared_tree_copy))
+        }
+    ));
+
+    // Clients are turned away on a seperate thread, so that slow ones can not hold up accepting
+    let acceptor_copy = acceptor.clone();
+    let rejecter = Arc::new(WorkerPool::new(
+        1,
+        REJECT_QUEUE_LENGTH,
+        move |client: TcpStream| reject_client(client, &get_current_acceptor(&acceptor_copy))
+    ));
+
+    // Start shutting down on SIGTERM / SIGINT and reload on SIGHUP
+    signals::listen();
+
+    // Start server thread(s), these return once the server is shutting down
+    let last_listener = listeners.pop().unwrap();
+    let mut server_threads = Vec::new();
+    for listener in listeners {
+        let pool_copy = pool.clone();
+        let rejecter_copy = rejecter.clone();
+        server_threads.push(thread::spawn(move || handle_server(listener, pool_copy, rejecter_copy)));
+    }
+    handle_server(last_listener, pool, rejecter);
+    for server_thread in server_threads {
+        match server_thread.join() {
+            Ok(_) => (),
+            Err(_) => ()
+        };
     }
-    else {
-        let acceptor_copy = acceptor.clone();
-        let tree_copy = tree.clone();
-        let listener0 = listeners.pop().unwrap();
-        let listener1 = listeners.pop().unwrap();
 
-        thread::spawn(move || handle_server(listener0, acceptor_copy, tree_copy));
-        handle_server(listener1, acceptor.clone(), tree.clone())
+    // Let active clients and dynamic objects finish before exiting
+    let grace_period = Duration::from_secs(get_current_tree(&shared_tree).settings.shutdown_grace_period);
+    if !shutdown::drain(grace_period) {
+        log("Warning: Clients or dynamic objects were still active after the grace period");
+        shutdown::kill_processes();
     }
+    remove_all_unique_files();
+
+    log("Info: Stopped Server");
 }
 
-fn handle_server(listener: TcpListener, acceptor: Arc<SslAcceptor>, tree: Arc<UrlTree>)
+fn get_current_tree(shared_tree: &SharedTree) -> Arc<UrlTree> {
+    match shared_tree.read() {
+        Ok(val) => val.clone(),
+        Err(poisoned) => poisoned.into_inner().clone()
+    }
+}
+
+fn get_current_acceptor(shared_acceptor: &SharedAcceptor) -> Arc<SslAcceptor> {
+    match shared_acceptor.read() {
+        Ok(val) => val.clone(),
+        Err(poisoned) => poisoned.into_inner().clone()
+    }
+}
+
+// Reloads the url tree on SIGHUP or when the watched files change
+fn watch_tree(shared_tree: SharedTree) {
+    let mut file_state = url_tree::get_file_state(&get_current_tree(&shared_tree).settings);
+    let mut last_check = Instant::now();
+
+    loop {
+        thread::sleep(Duration::from_secs(RELOAD_POLL_TIME));
+        let tree = get_current_tree(&shared_tree);
+        let mut reload = signals::take_reload_request();
+
+        // Check the settings file and root directory for changes
+        let watch_interval = tree.settings.watch_interval;
+        if watch_interval != 0 && last_check.elapsed().as_secs() >= watch_interval {
+            last_check = Instant::now();
+            if url_tree::get_file_state(&tree.settings) != file_state {
+                log("Info: Watched files changed, reloading url tree");
+                reload = true;
+            }
+        }
+
+        if reload {
+            reload_tree(&shared_tree);
+            file_state = url_tree::get_file_state(&get_current_tree(&shared_tree).settings);
+        }
+    }
+}
+
+// Swaps in a new url tree for new requests, the old one stays in service if it can not be built
+fn reload_tree(shared_tree: &SharedTree) {
+    let tree = match url_tree::try_get_url_tree() {
+        Ok(val) => val,
+        Err(err) => {
+            log(&format!("Error: Failed to reload the url tree, the old tree stays in service. {}", err));
+            return;
+        }
+    };
+
+    match shared_tree.write() {
+        Ok(mut val) => *val = Arc::new(tree),
+        Err(poisoned) => *poisoned.into_inner() = Arc::new(tree)
+    };
+    log("Info: Reloaded url tree");
+}
+
+fn handle_server(listener: TcpListener, pool: Arc<WorkerPool<(TcpStream, shutdown::ClientGuard)>>, rejecter: Arc<WorkerPool<TcpStream>>)
 {
-    for stream in listener.incoming() {
-        match stream {
-            Ok(client) => {
-                let acceptor = acceptor.clone();
-                let tree = tree.clone();
-
-                thread::spawn(move || {
-                    let client = match acceptor.accept(client) {
-                        Ok(val) => val,
-                        Err(_) => return
+    // The listener is polled so that it can stop accepting once the server is shutting down
+    expect_pretty(listener.set_nonblocking(true), "Critical Error: Failed to configure listener");
+
+    while !shutdown::is_shutting_down() {
+        match listener.accept() {
+            Ok((client, _)) => {
+                match client.set_nonblocking(false) {
+                    Ok(_) => (),
+                    Err(_) => continue
+                };
+
+                // Queued clients are counted as active already, so that draining waits for them.
+                // If all workers are busy and the queue is full, turn the client away.
+                // If the rejecter is busy as well, the connection is simply closed
+                if let Err((client, _)) = pool.queue((client, shutdown::ClientGuard::new())) {
+                    match rejecter.queue(client) {
+                        Ok(_) => (),
+                        Err(client) => drop(client)
                     };
-                    handle_client(client, tree);
-                });
+                }
+            },
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(ACCEPT_POLL_TIME));
             },
             Err(_) => continue
         }
     }
 }
 
-fn handle_client(mut client: SslStream<TcpStream>, tree: Arc<UrlTree>) {
-    let mut buffer = [0; BUFFER_SIZE];
+// Answers with server unavailable, this runs on the single rejecting thread so it is kept short
+fn reject_client(client: TcpStream, acceptor: &SslAcceptor) {
+    let timeout = Some(Duration::from_secs(REJECT_TIMEOUT));
+    match client.set_write_timeout(timeout) {
+        Ok(_) => (),
+        Err(_) => return
+    };
 
-    // Read and parse request from client
-    let num_bytes = match client.read(&mut buffer) {
+    let mut client = match acceptor.accept(DeadlineStream::new(client, timeout)) {
         Ok(val) => val,
-        Err(_) => {
-            shutdown_client(client);
+        Err(_) => return
+    };
+    let response = Response::new(StatusCode::ServerUnavailible, String::from("Server Unavailable"), Vec::new());
+    match client.write_all(&response.build()) {
+        Ok(_) => (),
+        Err(_) => ()
+    };
+    shutdown_client(client);
+}
+
+// A tcp stream which stops reading once its deadline has passed. The socket timeout is set to
+// the time left before each read, so slow clients can not stretch the time over many reads
+#[derive(Debug)]
+struct DeadlineStream {
+    stream: TcpStream,
+    deadline: Option<Instant>
+}
+
+impl DeadlineStream {
+    fn new(stream: TcpStream, timeout: Option<Duration>) -> Self {
+        DeadlineStream {
+            stream,
+            deadline: timeout.map(|val| Instant::now() + val)
+        }
+    }
+
+    // Without a deadline the read timeout of the socket applies to each read. The timeout left
+    // over from the last deadline is removed, so that it does not limit the following reads
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.deadline = timeout.map(|val| Instant::now() + val);
+        match self.deadline {
+            Some(_) => Ok(()),
+            None => self.stream.set_read_timeout(None)
+        }
+    }
+
+    fn has_timed_out(&self) -> bool {
+        match self.deadline {
+            Some(deadline) => Instant::now() >= deadline,
+            None => false
+        }
+    }
+}
+
+impl Read for DeadlineStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(deadline) = self.deadline {
+            let time_left = deadline.saturating_duration_since(Instant::now());
+            if time_left.as_millis() == 0 { // A timeout of 0 would block forever
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "The deadline has passed"));
+            }
+            self.stream.set_read_timeout(Some(time_left))?;
+        }
+
+        self.stream.read(buf)
+    }
+}
+
+impl Write for DeadlineStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl Deref for DeadlineStream {
+    type Target = TcpStream;
+
+    fn deref(&self) -> &TcpStream {
+        &self.stream
+    }
+}
+
+// Performs the tls handshake and applies the timeouts from the settings.
+// The guard keeps the client counted as active from the moment it was queued until it is done
+fn accept_client(client: TcpStream, _active: shutdown::ClientGuard, acceptor: &SslAcceptor, tree: Arc<UrlTree>) {
+    let settings = &tree.settings;
+    let peer = get_peer_name(&client);
+
+    let handshake_timeout = get_timeout(settings.handshake_timeout);
+    match client.set_write_timeout(handshake_timeout) {
+        Ok(_) => (),
+        Err(_) => return
+    };
+
+    // The handshake timeout covers the whole handshake
+    let mut client = match acceptor.accept(DeadlineStream::new(client, handshake_timeout)) {
+        Ok(val) => val,
+        Err(err) => {
+            let timed_out = match &err {
+                HandshakeError::WouldBlock(_) => true, // Blocking sockets only stop early on timeouts
+                HandshakeError::Failure(stream) => stream.get_ref().has_timed_out(),
+                HandshakeError::SetupFailure(_) => false
+            };
+            if settings.log && timed_out {
+                log(&format!("Warning: Timed out during the tls handshake. Peer: {}", peer));
+            }
             return;
         }
     };
 
-    // Check for oversized url
-    if num_bytes > 1026 {
-        let err_msg = ServerError::new(
-            String::from("Error: Url size was larger than 1024"),
-            StatusCode::BadRequest
-        );
+    // The read timeout covers the whole request line
+    match client.get_mut().set_timeout(get_timeout(settings.read_timeout)) {
+        Ok(_) => (),
+        Err(_) => return
+    };
+    match client.get_ref().set_write_timeout(get_timeout(settings.write_timeout)) {
+        Ok(_) => (),
+        Err(_) => return
+    };
 
-        let serve_errors = tree.settings.serve_errors;
-        let log = tree.settings.log;
-        match client.write(&get_err_response(err_msg, serve_errors, log)) {
-            Ok(_) => (),
-            Err(_) => ()
-        };
+    handle_client(client, tree);
+}
 
-        shutdown_client(client);
-        return;
-    }
+fn handle_client(mut client: SslStream<DeadlineStream>, tree: Arc<UrlTree>) {
+    // Read request line from client
+    let (buffer, header_len) = match read_request_line(&mut client, tree.settings.log) {
+        Ok(Some(val)) => val,
+        Ok(None) => { // Connection failed
+            shutdown_client(client);
+            return;
+        },
+        Err(err) => {
+            let serve_errors = tree.settings.serve_errors;
+            let log = tree.settings.log;
+            match client.write(&get_err_response(err, serve_errors, log)) {
+                Ok(_) => (),
+                Err(_) => ()
+            };
+            shutdown_client(client);
+            return;
+        }
+    };
+    let num_bytes = buffer.len();
+
+    // Upload bodies may be large, so from here on the read timeout applies to each read
+    let timeout = client.get_mut().set_timeout(None)
+        .and_then(|_| client.get_ref().set_read_timeout(get_timeout(tree.settings.read_timeout)));
+    match timeout {
+        Ok(_) => (),
+        Err(_) => {
+            shutdown_client(client);
+            return;
+        }
+    };
 
     // Parse the request
-    let mut request = match protocol::parse_request(&buffer[0..num_bytes]) {
+    let mut request = match protocol::parse_request(&buffer[0..header_len]) {
         Ok(val) => val,
         Err(err) => { // If bad request, return error status
             let serve_errors = tree.settings.serve_errors;
@@ -170,11 +456,111 @@ fn handle_client(mut client: SslStream<TcpStream>, tree: Arc<UrlTree>) {
         }
     };
 
+    // Requests for ports other than the one the client connected to are meant for another server
+    let local_port = match client.get_ref().local_addr() {
+        Ok(val) => val.port(),
+        Err(_) => protocol::GEMINI_PORT
+    };
+    if request.port != local_port {
+        let err = ServerError::new(
+            format!("Error: This server does not handle proxy requests. Port: {}", request.port),
+            StatusCode::ProxyRequestRefused
+        );
+        match client.write(&get_err_response(err, tree.settings.serve_errors, tree.settings.log)) {
+            Ok(_) => (),
+            Err(_) => ()
+        };
+        shutdown_client(client);
+        return;
+    }
+
     // Attach certificate to request if present
     let cert_option = client.ssl().peer_certificate();
     if let Some(cert) = &cert_option {
         request.certificate = Some(cert);
     }
+    request.peer_addr = client.get_ref().peer_addr().ok();
+
+    // Tell clients over the rate limit to slow down. The ip address is always limited, since
+    // new certificates cost nothing, the certificate only adds a limit of its own
+    if let Some(limit) = &tree.settings.rate_limit {
+        let mut keys = vec![match request.peer_addr {
+            Some(addr) => format!("ip:{}", addr.ip()),
+            None => String::from("unknown")
+        }];
+        if let (Some(cert), true) = (&request.certificate, limit.by_certificate) {
+            keys.push(format!("cert:{}", get_fingerprint(cert)));
+        }
+
+        if let Some(wait_time) = keys.iter().filter_map(|key| rate_limit::check(key, limit)).max() {
+            let err = ServerError {
+                message: wait_time.to_string(),
+                is_meta: true,
+                status_code: StatusCode::SlowDown
+            };
+            match client.write(&get_err_response(err, tree.settings.serve_errors, tree.settings.log)) {
+                Ok(_) => (),
+                Err(_) => ()
+            };
+            shutdown_client(client);
+            return;
+        }
+    }
+
+    // Reject certificates that are expired, not valid yet or have a broken self-signature
+    if let (Some(cert), true) = (&request.certificate, tree.settings.validate_client_certificates) {
+        if let Err(message) = tls::validate_client_certificate(cert) {
+            let err = ServerError {
+                message,
+                is_meta: true,
+                status_code: StatusCode::CertificateInvalid
+            };
+            match client.write(&get_err_response(err, tree.settings.serve_errors, tree.settings.log)) {
+                Ok(_) => (),
+                Err(_) => ()
+            };
+            shutdown_client(client);
+            return;
+        }
+    }
+
+    // Only uploads may send data after the request line
+    if request.upload.is_none() && header_len != num_bytes {
+        let err = ServerError::from_str("Error: Request contains data after \\r\\n", StatusCode::BadRequest);
+        match client.write(&get_err_response(err, tree.settings.serve_errors, tree.settings.log)) {
+            Ok(_) => (),
+            Err(_) => ()
+        };
+        shutdown_client(client);
+        return;
+    }
+
+    // Handle titan uploads
+    if request.upload.is_some() {
+        let received = buffer[header_len..num_bytes].to_vec();
+        let response = match handle_upload(&mut client, &request, received, &tree) {
+            Ok(val) => val.build(),
+            Err(err) => get_err_response(err, tree.settings.serve_errors, tree.settings.log)
+        };
+        match client.write_all(&response) {
+            Ok(_) => (),
+            Err(_) => ()
+        };
+        shutdown_client(client);
+        return;
+    }
+
+    // Forward requests for mounted upstream servers
+    if let Some(proxy_obj) = url_tree::find_path_object(&tree.proxies, &request.host, &request.path, |obj| (&obj.domain, &obj.link_path)) {
+        if let Err(err) = proxy::forward(&mut client, proxy_obj, &request) {
+            match client.write(&get_err_response(err, tree.settings.serve_errors, tree.settings.log)) {
+                Ok(_) => (),
+                Err(_) => ()
+            };
+        }
+        shutdown_client(client);
+        return;
+    }
 
     // Generate response and send it to client
     let mut bytes_written_total = 0;
@@ -182,7 +568,12 @@ fn handle_client(mut client: SslStream<TcpStream>, tree: Arc<UrlTree>) {
     while bytes_written_total < response.len() {
         let bytes_written = match client.write(&response[bytes_written_total..]) {
             Ok(val) => val,
-            Err(_) => return
+            Err(err) => {
+                if tree.settings.log && is_timeout(&err) {
+                    log(&format!("Warning: Timed out while writing the response. Peer: {}", get_peer_name(client.get_ref())));
+                }
+                return;
+            }
         };
         bytes_written_total += bytes_written;
     }
@@ -190,53 +581,318 @@ fn handle_client(mut client: SslStream<TcpStream>, tree: Arc<UrlTree>) {
     shutdown_client(client);
 }
 
-fn shutdown_client(mut client: SslStream<TcpStream>) {
-    match client.shutdown() {
-        Ok(_) => (),
-        Err(_) => ()
+// A timeout of 0 disables it
+fn get_timeout(seconds: u64) -> Option<Duration> {
+    match seconds {
+        0 => None,
+        val => Some(Duration::from_secs(val))
+    }
+}
+
+fn is_timeout(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut
+}
+
+fn get_peer_name(client: &TcpStream) -> String {
+    match client.peer_addr() {
+        Ok(val) => val.to_string(),
+        Err(_) => String::from("unknown")
+    }
+}
+
+// Reads until the end of the request line, returns all data read and the length of the line.
+// Titan uploads send their body right after the request line, so more data may have been read
+fn read_request_line(client: &mut SslStream<DeadlineStream>, log_timeout: bool) -> Result<Option<(Vec<u8>, usize)>> {
+    let too_large_err = || Err(ServerError::from_str(
+        "Error: Url size was larger than 1024",
+        StatusCode::BadRequest
+    ));
+
+    let mut data: Vec<u8> = Vec::with_capacity(BUFFER_SIZE);
+    let mut buffer = [0; BUFFER_SIZE];
+    loop {
+        // Check for end of request line
+        if let Some(index) = data.windows(2).position(|val| val == b"\r\n") {
+            if index + 2 > MAX_REQUEST_SIZE {
+                return too_large_err();
+            }
+
+            return Ok(Some((data, index + 2)));
+        }
+        if data.len() >= MAX_REQUEST_SIZE {
+            return too_large_err();
+        }
+
+        let num_bytes = match client.read(&mut buffer) {
+            Ok(0) => { // Client stopped sending before the end of the line
+                return Err(ServerError::from_str(
+                    "Error: Invalid request, \\r\\n was not present",
+                    StatusCode::BadRequest
+                ));
+            },
+            Ok(val) => val,
+            Err(err) => {
+                if log_timeout && (is_timeout(&err) || client.get_ref().has_timed_out()) {
+                    log(&format!("Warning: Timed out while reading the request. Peer: {}", get_peer_name(client.get_ref())));
+                }
+                return Ok(None);
+            }
+        };
+        data.extend_from_slice(&buffer[0..num_bytes]);
+    }
+}
+
+fn shutdown_client<S: Read + Write>(mut client: SslStream<S>) {
+    match client.shutdown() {
+        Ok(_) => (),
+        Err(_) => ()
+    }
+}
+
+fn handle_request(mut request: Request, tree: &UrlTree) -> Vec<u8> {
+    // If path points to root, switch with homepage
+    if request.path.trim() == "" && tree.settings.homepage.is_some() {
+        let path = tree.settings.homepage.as_ref().unwrap();
+        request.path = path.clone();
+    }
+
+    // Restricted paths are checked first, so that clients can not find out what exists there
+    if let Some(access_obj) = url_tree::find_path_object(&tree.access, &request.host, &request.path, |obj| (&obj.domain, &obj.link_path)) {
+        if let Err(err) = authorize_access(access_obj, &request.certificate) {
+            return get_err_response(err, tree.settings.serve_errors, tree.settings.log);
+        }
+    }
+
+    // Search for node and get data
+    let node = match search_in_tree(tree, &request.host, &request.path) {
+        Ok(val) => val,
+        Err(err) => return get_err_response(err, tree.settings.serve_errors, tree.settings.log)
+    };
+
+    // Redirects are answered without loading any data
+    if let Some(FileData { meta_data: FileType::Redirect(redirect), .. }) = &node.data {
+        let status_code = match redirect.permanent {
+            true => StatusCode::RedirectPermenent,
+            false => StatusCode::RedirectTemporary
+        };
+
+        return Response::new(status_code, redirect.target.clone(), Vec::new()).build();
+    }
+
+    // Removed resources are answered with gone and an optional custom message
+    if let Some(FileData { meta_data: FileType::Gone(gone), .. }) = &node.data {
+        let err = match &gone.message {
+            Some(message) => ServerError {
+                message: message.clone(),
+                is_meta: true,
+                status_code: StatusCode::Gone
+            },
+            None => ServerError::new(
+                format!("Error: Resource is gone. Path: {}", &request.path),
+                StatusCode::Gone
+            )
+        };
+
+        return get_err_response(err, tree.settings.serve_errors, tree.settings.log);
+    }
+
+    let (body, meta) = match get_resource(node, &request, tree) {
+        Ok(val) => val,
+        Err(err) => return get_err_response(err, tree.settings.serve_errors, tree.settings.log)
+    };
+
+    Response::new(StatusCode::Success, meta, body).build()
+}
+
+// Returns the meta field for successful responses
+fn get_meta(mime: &str, tree: &UrlTree) -> String {
+    let mut meta;
+    if mime.starts_with("text") {
+        if mime == "text/gemini" && tree.settings.default_lang.is_some() { 
+            meta = format!(
+                "text/gemini; lang={}",
+                &tree.settings.default_lang.as_ref().unwrap()
+            );
+        }
+        else {
+            meta = mime.to_string();
+        }
+
+        if let Some(charset) = &tree.settings.default_charset {
+            meta += &format!("; charset={}", &charset);
+        }
+    }
+    else {
+        meta = mime.to_string();
+    }
+
+    meta
+}
+
+fn handle_upload(client: &mut SslStream<DeadlineStream>, request: &Request, mut received: Vec<u8>, tree: &UrlTree) -> Result<Response> {
+    let upload = request.upload.as_ref().unwrap(); // Only called for uploads
+
+    // Find the upload object for the path
+    let upload_obj = match url_tree::find_path_object(&tree.uploads, &request.host, &request.path, |obj| (&obj.domain, &obj.link_path)) {
+        Some(val) => val,
+        None => {
+            return Err(ServerError::new(
+                format!("Error: Uploads are not enabled for this path. Path: {}", &request.path),
+                StatusCode::PermanentFailure
+            ));
+        }
+    };
+    authorize_upload(upload_obj, upload, &request.certificate)?;
+
+    // Check size
+    let max_size = upload_obj.max_size.unwrap(); // Max size is always set at this point
+    if upload.size > max_size {
+        return Err(ServerError::new(
+            format!("Error: The upload is larger than the maximum size of {} bytes", max_size),
+            StatusCode::BadRequest
+        ));
+    }
+
+    // Read the rest of the body
+    if received.len() as u64 > upload.size {
+        return Err(ServerError::from_str("Error: The upload is larger than its given size", StatusCode::BadRequest));
+    }
+    let received_len = received.len();
+    received.resize(upload.size as usize, 0);
+    if let Err(err) = client.read_exact(&mut received[received_len..]) {
+        return Err(ServerError::new(
+            format!("Error: Failed to read the upload. {}", err),
+            StatusCode::BadRequest
+        ));
+    }
+
+    // Either pass the upload to a dynamic object or save it
+    let directory = match &upload_obj.directory {
+        Some(val) => val,
+        None => {
+            let node = search_in_tree(tree, &request.host, &request.path)?;
+            let dynamic_obj = match &node.data {
+                Some(FileData { meta_data: FileType::Dynamic(val), .. }) => val,
+                _ => {
+                    return Err(ServerError::new(
+                        format!("Error: Uploads are only accepted by dynamic objects. Path: {}", &request.path),
+                        StatusCode::PermanentFailure
+                    ));
+                }
+            };
+
+            let (body, meta) = load_dynamic_content(dynamic_obj, Some(&request), Some((upload, received)), tree.settings.log)?;
+            let meta = match meta {
+                Some(val) => val,
+                None => get_meta(node.data.as_ref().unwrap().meta_data.get_mime_type(), tree)
+            };
+
+            return Ok(Response::new(StatusCode::Success, meta, body));
+        }
+    };
+
+    let rel_path = Path::from_str(&request.path).skip_components(Path::from_str(&upload_obj.link_path).depth());
+    if rel_path.is_root() {
+        return Err(ServerError::from_str("Error: Uploads require a file name", StatusCode::BadRequest));
+    }
+    let file_path = Path::from_parent(&Path::from_str(directory), &rel_path);
+    if let Ok(canonical_path) = fs::canonicalize(&file_path.original) {
+        if tree.config_files.contains(&canonical_path) {
+            return Err(ServerError::from_str("Error: Config files can not be replaced by uploads", StatusCode::PermanentFailure));
+        }
+    }
+    save_upload(&file_path, &received)?;
+
+    // Send the client to the uploaded resource
+    let authority = match request.port {
+        protocol::GEMINI_PORT => request.host.to_string(),
+        port => format!("{}:{}", &request.host, port)
+    };
+    Ok(Response::new(
+        StatusCode::RedirectTemporary,
+        format!("gemini://{}/{}", authority, protocol::encode_path(&request.path)),
+        Vec::new()
+    ))
+}
+
+fn authorize_access(access_obj: &AccessObject, certificate: &Option<&X509>) -> Result<()> {
+    let cert = match certificate {
+        Some(val) => val,
+        None => {
+            return Err(ServerError {
+                message: String::from("A certificate is required to access this content"),
+                is_meta: true,
+                status_code: StatusCode::CertificateRequired
+            });
+        }
+    };
+
+    if access_obj.certificates.contains(&get_fingerprint(cert)) {
+        Ok(())
+    }
+    else {
+        Err(ServerError {
+            message: String::from("This certificate is not authorized to access this content"),
+            is_meta: true,
+            status_code: StatusCode::CertificateUnauthorized
+        })
     }
 }
 
-fn handle_request(mut request: Request, tree: &UrlTree) -> Vec<u8> {
-    // If path points to root, switch with homepage
-    if request.path.trim() == "" && tree.settings.homepage.is_some() {
-        let path = tree.settings.homepage.as_ref().unwrap();
-        request.path = path.clone();
+// A matching token or an allowed certificate is required to upload
+fn authorize_upload(upload_obj: &UploadObject, upload: &Upload, certificate: &Option<&X509>) -> Result<()> {
+    // Tokens are compared by their digests in constant time, so the comparison does not leak how much of a guess is correct
+    if let (Some(token), Some(given_token)) = (&upload_obj.token, &upload.token) {
+        if memcmp::eq(&sha256(token.as_bytes()), &sha256(given_token.as_bytes())) {
+            return Ok(());
+        }
     }
 
-    // Search for node and get data
-    let node = match search_in_tree(tree, &request.domain, &request.path) {
-        Ok(val) => val,
-        Err(err) => return get_err_response(err, tree.settings.serve_errors, tree.settings.log)
-    };
-    let (body, mime) = match get_resource(node, &request.query, &request.certificate) {
-        Ok(val) => val,
-        Err(err) => return get_err_response(err, tree.settings.serve_errors, tree.settings.log)
+    let authorized = match certificate {
+        Some(cert) => upload_obj.certificates.contains(&get_fingerprint(cert)), // An empty list allows no certificate
+        None if upload.token.is_none() => {
+            return Err(ServerError {
+                message: String::from("A certificate or token is required to upload here"),
+                is_meta: true,
+                status_code: StatusCode::CertificateRequired
+            });
+        },
+        None => false
     };
 
-    // Create meta field
-    let mut meta;
-    if mime.starts_with("text") {
-        if mime == "text/gemini" && tree.settings.default_lang.is_some() { 
-            meta = format!(
-                "text/gemini; lang={}",
-                &tree.settings.default_lang.as_ref().unwrap()
-            );
-        }
-        else {
-            meta = mime.to_string();
-        }
+    match authorized {
+        true => Ok(()),
+        false => Err(ServerError {
+            message: String::from("Not authorized to upload here"),
+            is_meta: true,
+            status_code: StatusCode::CertificateUnauthorized
+        })
+    }
+}
 
-        if let Some(charset) = &tree.settings.default_charset {
-            meta += &format!("; charset={}", &charset);
+// An empty upload removes the file
+fn save_upload(file_path: &Path, data: &[u8]) -> Result<()> {
+    let save_error = |err: &dyn Display| Err(ServerError::new(
+        format!("Error: The upload could not be saved. {}", err),
+        StatusCode::TemporaryFailure
+    ));
+
+    if data.len() == 0 {
+        return match fs::remove_file(&file_path.original) {
+            Ok(_) => Ok(()),
+            Err(err) => save_error(&err)
+        };
+    }
+
+    if let Some(parent) = std::path::Path::new(&file_path.original).parent() {
+        if let Err(err) = fs::create_dir_all(parent) {
+            return save_error(&err);
         }
     }
-    else {
-        meta = mime.to_string();
+    match fs::write(&file_path.original, data) {
+        Ok(_) => Ok(()),
+        Err(err) => save_error(&err)
     }
-
-
-    Response::new(StatusCode::Success, meta, body).build()
 }
 
 fn search_in_tree<'a>(tree: &'a UrlTree, domain: &str, path: &str) -> Result<&'a UrlNode> {
@@ -295,13 +951,24 @@ fn get_err_response(err: ServerError, serve_errors: bool, log: bool) -> Vec<u8>
     response.build()
 }
 
-// Returns binary data and mime-type
-fn get_resource<'a>(node: &'a UrlNode, query: &Option<String>, certificate: &Option<&X509>) -> Result<(Vec<u8>, &'a str)> {
+// Returns binary data and meta field
+fn get_resource(node: &UrlNode, request: &Request, tree: &UrlTree) -> Result<(Vec<u8>, String)> {
     let not_found_err = || Err(ServerError::new(
         String::from("Error: Resource not found"),
         StatusCode::NotFound
     ));
 
+    // Protected files and links are only served to clients with a certificate
+    if let Some(FileData { meta_data, .. }) = &node.data {
+        if meta_data.requires_certificate() && request.certificate.is_none() {
+            return Err(ServerError {
+                message: String::from("A certificate is required to access this content"),
+                is_meta: true,
+                status_code: StatusCode::CertificateRequired
+            });
+        }
+    }
+
     let result = match &node.data {
         Some( // Case data is already loaded
             FileData {
@@ -310,11 +977,11 @@ fn get_resource<'a>(node: &'a UrlNode, query: &Option<String>, certificate: &Opt
             }
         ) => {
             let binary_data = binary_data.clone();
-            let mime_type = meta_data.get_mime_type();
+            let meta = get_meta(meta_data.get_mime_type(), tree);
 
             Ok((
                 binary_data,
-                mime_type
+                meta
             ))
         },
         Some( // Case data has not been loaded / Dynamic
@@ -323,12 +990,15 @@ fn get_resource<'a>(node: &'a UrlNode, query: &Option<String>, certificate: &Opt
                 binary_data: None
             }
         ) => {
-            let binary_data = load_data(meta_data, query, certificate)?;
-            let mime_type = meta_data.get_mime_type();
+            let (binary_data, meta) = load_data(meta_data, request, tree.settings.log)?;
+            let meta = match meta { // Programs may send their own meta field
+                Some(val) => val,
+                None => get_meta(meta_data.get_mime_type(), tree)
+            };
 
             Ok((
                 binary_data,
-                mime_type
+                meta
             ))
         }
         // Case node does not exist (file not found)
@@ -338,7 +1008,7 @@ fn get_resource<'a>(node: &'a UrlNode, query: &Option<String>, certificate: &Opt
     result
 }
 
-fn load_data(file_type: &FileType, query: &Option<String>, certificate: &Option<&X509>) -> Result<Vec<u8>> {
+fn load_data(file_type: &FileType, request: &Request, log: bool) -> Result<(Vec<u8>, Option<String>)> {
     let internal_error = |err: &dyn Display| Err(ServerError::new(
         format!("Error: Resource could not be retrieved. {}", err),
         StatusCode::TemporaryFailure
@@ -346,43 +1016,64 @@ fn load_data(file_type: &FileType, query: &Option<String>, certificate: &Option<
     
     if let FileType::Normal(val) = file_type { // For normal and link files read loaded data or load page
         match fs::read(&val.path.original) {
-            Ok(val) => return Ok(val),
+            Ok(val) => return Ok((val, None)),
             Err(err) => return internal_error(&err)
         }
     }
     else if let FileType::Link(val) = file_type {
         match fs::read(&val.file_path) {
-            Ok(val) => return Ok(val),
+            Ok(val) => return Ok((val, None)),
             Err(err) => return internal_error(&err)
         }
     }
     else if let FileType::Dynamic(val) = file_type { // For dynamic content either retrieve cache or generate
         if val.cache {
-            return get_cached_data(val);
+            return get_cached_data(val, log);
         }
 
-        return load_dynamic_content(val, query, certificate);
+        return load_dynamic_content(val, Some(request), None, log);
     }
     
     internal_error(&"")
 }
 
-fn get_cached_data(dynamic_object: &DynamicObject) -> Result<Vec<u8>> {
+// Content read from the cache is served with the mime-type of the dynamic object
+fn get_cached_data(dynamic_object: &DynamicObject, log: bool) -> Result<(Vec<u8>, Option<String>)> {
     let file_path = format!("{}/{}", &*CACHE_DIR, get_hash(dynamic_object));
     match fs::read(file_path) {
-        Ok(val) => Ok(val),
-        Err(_) => load_dynamic_content(dynamic_object, &None, &None)
+        Ok(val) => Ok((val, None)),
+        Err(_) => load_dynamic_content(dynamic_object, None, None, log)
     }
 }
 
-fn load_dynamic_content(dynamic_object: &DynamicObject, query: &Option<String>, certificate: &Option<&X509>) -> Result<Vec<u8>> {
+// Runs a dynamic object, the request is not present when generating content for the cache
+// The returned meta is only set if the program has sent its own response header
+fn load_dynamic_content(dynamic_object: &DynamicObject, request: Option<&Request>, upload: Option<(&Upload, Vec<u8>)>, log: bool) -> Result<(Vec<u8>, Option<String>)> {
     let cgi_error = |err: &dyn Display| Err(ServerError::new(
         format!("Error: Process failed to generate content. {}", err),
         StatusCode::CGIError
     ));
+    let query = request.and_then(|request| request.raw_query.as_ref());
+    let certificate = request.and_then(|request| request.certificate);
+    let is_cgi = dynamic_object.cgi_mode != CgiMode::Legacy;
     
-    // Get the file path
-    let (temp_file_path, temp_file_num) = get_unique_file_path()?;
+    // Get the file path, programs writing to stdout do not need one
+    let temp_file = match dynamic_object.cgi_mode {
+        CgiMode::Stdout => None,
+        _ => Some(get_unique_file_path()?)
+    };
+
+    // Programs running as another user are given their file, as they can not create it
+    #[cfg(unix)]
+    {
+        if let (Some((temp_file_path, _)), true) = (&temp_file, dynamic_object.uid.is_some() || dynamic_object.gid.is_some()) {
+            let result = fs::File::create(temp_file_path)
+                .and_then(|_| std::os::unix::fs::chown(temp_file_path, dynamic_object.uid, dynamic_object.gid));
+            if let Err(err) = result {
+                return cgi_error(&err);
+            }
+        }
+    }
 
     // Create process
     let mut process = Command::new(&dynamic_object.program_path);
@@ -398,23 +1089,39 @@ fn load_dynamic_content(dynamic_object: &DynamicObject, query: &Option<String>,
         process.args(dynamic_object.args.clone());
     }
 
-    // Add path name
-    process.arg(
-        format!(
-            "unique_file_path='{}'",
-            temp_file_path
-        )
-    );
-
-    // Handle query
-    if let Some(query_options) = &dynamic_object.query {
-        if let Some(query_value) = query {
+    // Add path name, cgi programs get it together with the standard variables
+    if is_cgi {
+        process.envs(get_cgi_env(dynamic_object, request));
+    }
+    match &temp_file {
+        Some((temp_file_path, _)) if is_cgi => {
+            process.env("UNIQUE_FILE_PATH", temp_file_path);
+        },
+        Some((temp_file_path, _)) => {
             process.arg(
                 format!(
-                    "query='{}'",
-                    query_value
+                    "unique_file_path='{}'",
+                    temp_file_path
                 )
             );
+        },
+        None => {
+            process.stdout(Stdio::piped());
+        }
+    }
+
+    // Handle query
+    if let Some(query_options) = &dynamic_object.query {
+        if let Some(query_value) = query {
+            // Cgi programs read QUERY_STRING instead, escape ' and " for command line
+            if !is_cgi {
+                process.arg(
+                    format!(
+                        "query='{}'",
+                        query_value.replace("'", "%27").replace("\"", "%22")
+                    )
+                );
+            }
         }
         else {
             let status_code = match query_options.private {
@@ -444,13 +1151,18 @@ fn load_dynamic_content(dynamic_object: &DynamicObject, query: &Option<String>,
                 Err(err) => return cgi_error(&err)
             }
 
-            // Add command line argument for certifcate file path
-            process.arg(
-                format!(
-                    "cert_file_path='{}'",
-                    cert_file_path
-                )
-            );
+            // Add command line argument or variable for certifcate file path
+            if is_cgi {
+                process.env("CERT_FILE_PATH", &cert_file_path);
+            }
+            else {
+                process.arg(
+                    format!(
+                        "cert_file_path='{}'",
+                        cert_file_path
+                    )
+                );
+            }
 
             cert_file_info = Some((cert_file_path, cert_file_num));
         }
@@ -467,57 +1179,438 @@ fn load_dynamic_content(dynamic_object: &DynamicObject, query: &Option<String>,
         cert_file_info = None;
     }
 
+    // Uploads are passed through stdin
+    if let Some((upload_info, _)) = &upload {
+        if is_cgi {
+            process.env("CONTENT_TYPE", &upload_info.mime);
+            process.env("CONTENT_LENGTH", upload_info.size.to_string());
+        }
+        else {
+            process.arg(format!("upload_mime='{}'", upload_info.mime.replace("'", "%27").replace("\"", "%22")));
+            process.arg(format!("upload_size='{}'", upload_info.size));
+        }
+        process.stdin(Stdio::piped());
+    }
+
+    // Stderr is captured for the log
+    process.stderr(Stdio::piped());
+
+    // The process leads its own process group, so it can be stopped together with its children
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        process.process_group(0);
+    }
+    if let Err(err) = apply_limits(&mut process, dynamic_object) {
+        return cgi_error(&err);
+    }
+
     // Start process
     let mut process = match process.spawn() {
         Ok(val) => val,
         Err(err) => return cgi_error(&err)
     };
-
-    // Poll process for completion, exit if time over
+    let pid = process.id();
+    let (stdin, stdout, stderr) = (process.stdin.take(), process.stdout.take(), process.stderr.take());
+    let process = Arc::new(Mutex::new(process));
+    let _active = shutdown::ProcessGuard::new(pid, &process); // Counted until the process is done
     let start_time = Instant::now();
+
+    // Write the upload on a seperate thread so a process that does not read it can not block
+    if let (Some((_, data)), Some(mut stdin)) = (upload, stdin) {
+        thread::spawn(move || {
+            match stdin.write_all(&data) {
+                Ok(_) => (),
+                Err(_) => ()
+            };
+        });
+    }
+
+    // Read stdout on a seperate thread so the process can not block on a full pipe.
+    // One byte more than allowed is read to find programs exceeding the output limit, these are killed
+    let max_output_size = dynamic_object.max_output_size;
+    let stdout_reader = stdout.map(|mut stdout| {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut output = Vec::new();
+            let result = match max_output_size {
+                Some(max) => (&mut stdout).take(max + 1).read_to_end(&mut output),
+                None => stdout.read_to_end(&mut output)
+            };
+            if let Some(max) = max_output_size {
+                if output.len() as u64 > max {
+                    shutdown::kill_process(pid);
+                }
+            }
+
+            match sender.send(result.map(|_| output)) {
+                Ok(_) => (),
+                Err(_) => ()
+            };
+        });
+        receiver
+    });
+    let stderr_reader = stderr.map(read_stderr);
+
+    // Wait for the process on a seperate thread, which reaps it even after it has been stopped
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        match sender.send(shutdown::wait_process(&process)) {
+            Ok(_) => (),
+            Err(_) => ()
+        };
+    });
+
     let gen_time = dynamic_object.gen_time.unwrap(); // gen_time is always set at this point
-    while start_time.elapsed().as_secs() < gen_time {
-        let poll_exit = process.try_wait();
-        if let Ok(Some(status)) = poll_exit {
-            // If a status code has been returned, either ignore it (if exited normally) or return as error (for self-determined gemini response codes)
-            if let Some(status_code) = status.code() {
-                if status_code != 0 && status_code != 20 {
-                    let message = match String::from_utf8(read_and_remove(&temp_file_path, temp_file_num)?) {
-                        Ok(val) => val,
-                        Err(_) => return cgi_error(&"The provided meta field for the response was not valid utf-8")
-                    };
-                    let status_code = match StatusCode::from_i32(status_code) {
-                        Some(val) => val,
-                        None => return cgi_error(&format!("Invalid status code {} returned", status_code))
-                    };
+    let result = receiver.recv_timeout(Duration::from_secs(gen_time));
+
+    // If certificate file has been created, remove it
+    if let Some((cert_file_path, cert_file_num)) = cert_file_info {
+        remove_unique_file(&cert_file_path, cert_file_num);
+    }
+
+    let (status, output) = match result {
+        Ok(Ok(status)) => {
+            let output = match temp_file {
+                Some((temp_file_path, temp_file_num)) => get_file_output(status, &temp_file_path, temp_file_num, max_output_size),
+                None => {
+                    // Children may still hold stdout open, they are only waited for until the time is over
+                    let time_left = Duration::from_secs(gen_time).saturating_sub(start_time.elapsed());
+                    get_stdout_output(status, stdout_reader, max_output_size, pid, time_left)
+                }
+            };
+            (Some(status), output)
+        },
+        Ok(Err(err)) => (None, cgi_error(&err)),
+        Err(_) => {
+            let status = stop_process(pid, &receiver, dynamic_object, gen_time, log);
+            (status, cgi_error(&"Process did not exit within the expected time"))
+        }
+    };
+
+    // Stderr of failed processes is logged, that of successful ones only if enabled
+    let failed = matches!(&output, Err(ServerError { status_code: StatusCode::CGIError, .. }));
+    if let (Some(stderr_reader), true) = (stderr_reader, log && (failed || dynamic_object.log_stderr_on_success)) {
+        log_stderr(dynamic_object, status, start_time.elapsed(), stderr_reader, failed);
+    }
+
+    output
+}
+
+// Describes an unsuccessful exit, naming the limit if the process was stopped for exceeding one
+fn get_exit_reason(status: ExitStatus) -> String {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        match status.signal() {
+            Some(libc::SIGXCPU) => return String::from("Process exceeded its CPU time limit"),
+            Some(libc::SIGXFSZ) => return String::from("Process exceeded its output limit"),
+            _ => ()
+        }
+    }
+
+    format!("Process exited unsuccessfully ({})", status)
+}
+
+// Applies the resource limits and the user of a dynamic object to its process before the program starts
+#[cfg(unix)]
+fn apply_limits(process: &mut Command, dynamic_object: &DynamicObject) -> io::Result<()> {
+    use std::os::unix::process::CommandExt;
 
-                    // If certificate file has been created, remove it
-                    if let Some((cert_file_path, cert_file_num)) = cert_file_info {
-                        remove_unique_file(&cert_file_path, cert_file_num);
+    // The file size limit is one byte above the output limit, so that exceeding it can be detected.
+    // The hard CPU limit is one second above the soft limit, at which SIGXCPU is sent
+    let output_limit = match dynamic_object.cgi_mode {
+        CgiMode::Stdout => None, // Enforced while reading stdout, files written by the program are not limited
+        _ => dynamic_object.max_output_size.map(|val| (val + 1, val + 1))
+    };
+    let limits = [
+        (libc::RLIMIT_FSIZE, output_limit),
+        (libc::RLIMIT_AS, dynamic_object.max_memory.map(|val| (val, val))),
+        (libc::RLIMIT_CPU, dynamic_object.max_cpu_time.map(|val| (val, val + 1))),
+        (libc::RLIMIT_NOFILE, dynamic_object.max_open_files.map(|val| (val, val)))
+    ];
+    let uid = dynamic_object.uid;
+
+    // Without a configured group the primary group of the user is used, otherwise the process would keep the group of the server
+    let gid = match (uid, dynamic_object.gid) {
+        (Some(uid), None) => Some(get_primary_group(uid)?),
+        (_, gid) => gid
+    };
+
+    // Only async-signal-safe functions may be called after forking
+    unsafe {
+        process.pre_exec(move || {
+            for (resource, limit) in limits.iter() {
+                if let Some((soft, hard)) = limit {
+                    let rlimit = libc::rlimit {
+                        rlim_cur: *soft as libc::rlim_t,
+                        rlim_max: *hard as libc::rlim_t
+                    };
+                    if libc::setrlimit(*resource, &rlimit) != 0 {
+                        return Err(io::Error::last_os_error());
                     }
+                }
+            }
 
-                    return Err(ServerError {
-                        message,
-                        status_code,
-                        is_meta: true
-                    });
+            // Supplementary groups of the server are dropped when switching users as root
+            if (uid.is_some() || gid.is_some()) && libc::geteuid() == 0 && libc::setgroups(0, std::ptr::null()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if let Some(gid) = gid {
+                if libc::setgid(gid) != 0 {
+                    return Err(io::Error::last_os_error());
                 }
-                // If status was ok default case is used
             }
-            
-            // If certificate file has been created, remove it
-            if let Some((cert_file_path, cert_file_num)) = cert_file_info {
-                remove_unique_file(&cert_file_path, cert_file_num);
+            if let Some(uid) = uid {
+                if libc::setuid(uid) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
             }
-            // Return the data read from the temp file and remove file
-            return read_and_remove(&temp_file_path, temp_file_num);
+
+            Ok(())
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn apply_limits(_process: &mut Command, _dynamic_object: &DynamicObject) -> io::Result<()> {
+    Ok(())
+}
+
+// Looks up the primary group of a user in the user database. This is done before forking,
+// because the lookup is not async-signal-safe
+#[cfg(unix)]
+fn get_primary_group(uid: u32) -> io::Result<u32> {
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; PASSWD_BUFFER_SIZE];
+    let mut result: *mut libc::passwd = std::ptr::null_mut();
+
+    let code = unsafe { libc::getpwuid_r(uid, &mut passwd, buffer.as_mut_ptr(), buffer.len(), &mut result) };
+    if code != 0 {
+        return Err(io::Error::from_raw_os_error(code));
+    }
+    if result.is_null() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("The user {} does not exist, a gid has to be set for it", uid)
+        ));
+    }
+
+    Ok(passwd.pw_gid)
+}
+
+// Reads stderr of a process on a seperate thread. Output beyond MAX_STDERR_SIZE is dropped,
+// the flag is set if this happened
+fn read_stderr(mut stderr: ChildStderr) -> Receiver<(Vec<u8>, bool)> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut output = Vec::new();
+        match (&mut stderr).take(MAX_STDERR_SIZE).read_to_end(&mut output) {
+            Ok(_) => (),
+            Err(_) => ()
+        };
+        let truncated = match io::copy(&mut stderr, &mut io::sink()) {
+            Ok(len) => len > 0,
+            Err(_) => false
+        };
+
+        match sender.send((output, truncated)) {
+            Ok(_) => (),
+            Err(_) => ()
+        };
+    });
+
+    receiver
+}
+
+fn log_stderr(dynamic_object: &DynamicObject, status: Option<ExitStatus>, duration: Duration, stderr_reader: Receiver<(Vec<u8>, bool)>, failed: bool) {
+    // Children which still hold stderr open are not waited for
+    let (output, truncated) = match stderr_reader.recv_timeout(Duration::from_millis(STDERR_WAIT_TIME)) {
+        Ok(val) => val,
+        Err(_) => return
+    };
+    if output.is_empty() {
+        return;
+    }
+
+    let status = match status {
+        Some(val) => val.to_string(),
+        None => String::from("no exit status")
+    };
+    log(&format!(
+        "{}: The process for {} wrote to stderr ({}, {:.2} seconds{}):\n{}",
+        if failed { "Warning" } else { "Info" },
+        &dynamic_object.link_path,
+        status,
+        duration.as_secs_f64(),
+        if truncated { ", truncated" } else { "" },
+        String::from_utf8_lossy(&output).trim_end()
+    ));
+}
+
+// Stops a process which has run out of time, first with SIGTERM and then with SIGKILL.
+// Both are sent to its whole process group. Returns the exit status if the process was reaped
+fn stop_process(pid: u32, receiver: &Receiver<io::Result<ExitStatus>>, dynamic_object: &DynamicObject, gen_time: u64, log: bool) -> Option<ExitStatus> {
+    shutdown::terminate_process(pid);
+    let terminated = receiver.recv_timeout(Duration::from_secs(KILL_GRACE_TIME));
+
+    // Children which have outlived the process are killed either way
+    shutdown::kill_process(pid);
+    if let Ok(result) = terminated {
+        if log {
+            crate::log(&format!(
+                "Warning: The process for {} did not exit within {} seconds and was terminated",
+                &dynamic_object.link_path, gen_time
+            ));
         }
-        else {
-            continue;
+        return result.ok();
+    }
+
+    let reaped = receiver.recv_timeout(Duration::from_secs(KILL_GRACE_TIME));
+    if log {
+        crate::log(&format!(
+            "Warning: The process for {} did not exit within {} seconds and was killed{}",
+            &dynamic_object.link_path, gen_time, if reaped.is_ok() { "" } else { ", but could not be reaped" }
+        ));
+    }
+    reaped.ok().and_then(|result| result.ok())
+}
+
+// Reads the output of a program which has written to its unique file
+fn get_file_output(status: ExitStatus, temp_file_path: &str, temp_file_num: u64, max_output_size: Option<u64>) -> Result<(Vec<u8>, Option<String>)> {
+    let cgi_error = |err: &dyn Display| Err(ServerError::new(
+        format!("Error: Process failed to generate content. {}", err),
+        StatusCode::CGIError
+    ));
+
+    // Processes stopped by a signal (e.g. for exceeding a limit) have not finished their output
+    if status.code().is_none() {
+        remove_unique_file(temp_file_path, temp_file_num);
+        return cgi_error(&get_exit_reason(status));
+    }
+    let output_size = match fs::metadata(temp_file_path) {
+        Ok(val) => val.len(),
+        Err(_) => 0
+    };
+    if let Some(max) = max_output_size {
+        if output_size > max {
+            remove_unique_file(temp_file_path, temp_file_num);
+            return cgi_error(&format!("Process exceeded its output limit of {} bytes", max));
+        }
+    }
+
+    // If a status code has been returned, either ignore it (if exited normally) or return as error (for self-determined gemini response codes)
+    if let Some(status_code) = status.code() {
+        if status_code != 0 && status_code != 20 {
+            let message = match String::from_utf8(read_and_remove(temp_file_path, temp_file_num)?) {
+                Ok(val) => val,
+                Err(_) => return cgi_error(&"The provided meta field for the response was not valid utf-8")
+            };
+            let status_code = match StatusCode::from_i32(status_code) {
+                Some(val) => val,
+                None => return cgi_error(&format!("Invalid status code {} returned", status_code))
+            };
+
+            return Err(ServerError {
+                message,
+                status_code,
+                is_meta: true
+            });
+        }
+        // If status was ok default case is used
+    }
+
+    // Return the data read from the temp file and remove file
+    Ok((read_and_remove(temp_file_path, temp_file_num)?, None))
+}
+
+// Reads the response a program has written to stdout, it starts with a gemini response header
+fn get_stdout_output(status: ExitStatus, stdout_reader: Option<Receiver<io::Result<Vec<u8>>>>, max_output_size: Option<u64>, pid: u32, time_left: Duration) -> Result<(Vec<u8>, Option<String>)> {
+    let cgi_error = |err: &dyn Display| Err(ServerError::new(
+        format!("Error: Process failed to generate content. {}", err),
+        StatusCode::CGIError
+    ));
+
+    let mut output = match stdout_reader.map(|reader| reader.recv_timeout(time_left)) {
+        Some(Ok(Ok(val))) => val,
+        Some(Err(RecvTimeoutError::Timeout)) => {
+            shutdown::kill_process(pid); // Stops the children holding stdout open
+            return cgi_error(&"Process did not close its output within the expected time");
+        },
+        _ => return cgi_error(&"The output of the process could not be read")
+    };
+    if let Some(max) = max_output_size {
+        if output.len() as u64 > max {
+            return cgi_error(&format!("Process exceeded its output limit of {} bytes", max));
+        }
+    }
+    if !status.success() {
+        return cgi_error(&get_exit_reason(status));
+    }
+
+    // Split off the header, which has to be terminated by \r\n
+    let search_len = output.len().min(protocol::MAX_HEADER_SIZE);
+    let header_len = match output[..search_len].windows(2).position(|val| val == b"\r\n") {
+        Some(val) => val,
+        None => return cgi_error(&"The response header was missing or too long")
+    };
+    let body = output.split_off(header_len + 2);
+    let header = str::from_utf8(&output[..header_len]).ok().and_then(protocol::parse_header);
+
+    // The body is only sent for successful responses
+    match header {
+        Some((StatusCode::Success, "")) => Ok((body, Some(String::from("text/gemini")))),
+        Some((StatusCode::Success, meta)) => Ok((body, Some(meta.to_string()))),
+        Some((status_code, meta)) => Err(ServerError {
+            message: meta.to_string(),
+            status_code,
+            is_meta: true
+        }),
+        None => cgi_error(&"The response header was invalid")
+    }
+}
+
+// Returns the standard CGI variables, request values are left out if there is no request
+fn get_cgi_env(dynamic_object: &DynamicObject, request: Option<&Request>) -> Vec<(&'static str, String)> {
+    let mut vars = vec![
+        ("GATEWAY_INTERFACE", String::from("CGI/1.1")),
+        ("SERVER_PROTOCOL", String::from("GEMINI")),
+        ("SERVER_SOFTWARE", format!("aerozine/{}", env!("CARGO_PKG_VERSION")))
+    ];
+
+    let request = match request {
+        Some(val) => val,
+        None => {
+            if let Some(domain) = &dynamic_object.domain {
+                vars.push(("SERVER_NAME", domain.clone()));
+            }
+            return vars;
+        }
+    };
+
+    vars.push(("SERVER_NAME", request.host.clone()));
+    vars.push(("SERVER_PORT", request.port.to_string()));
+    vars.push(("SCRIPT_NAME", format!("/{}", request.path)));
+    vars.push(("PATH_INFO", String::new()));
+    vars.push(("QUERY_STRING", request.raw_query.clone().unwrap_or_default()));
+    vars.push(("GEMINI_URL", request.url.clone()));
+    if let Some(addr) = request.peer_addr {
+        vars.push(("REMOTE_ADDR", addr.ip().to_string()));
+    }
+
+    // Client certificate, present whenever the client sent one
+    if let Some(cert) = request.certificate {
+        vars.push(("AUTH_TYPE", String::from("CERTIFICATE")));
+        vars.push(("TLS_CLIENT_HASH", format!("SHA256:{}", get_fingerprint(cert))));
+        vars.push(("TLS_CLIENT_SUBJECT", tls::get_subject(cert)));
+        if let Some(date) = tls::get_expiry_date(cert) {
+            vars.push(("TLS_CLIENT_NOT_AFTER", date));
         }
     }
 
-    cgi_error(&"Process did not exit within the expected time or exited without producing a result")
+    vars
 }
 
 fn format_certificate(certificate: &X509) -> String {
@@ -531,15 +1624,7 @@ fn format_certificate(certificate: &X509) -> String {
     };
 
     // Get fingerprint
-    let fingerprint = match certificate.digest(MessageDigest::sha256()) {
-        Ok(digest) => {
-            digest.as_ref()
-                .iter()
-                .map(|val| format!("{:02X}", val))
-                .collect()
-        },
-        Err(_) => String::from("Error")
-    };
+    let fingerprint = get_fingerprint(certificate);
 
     // Get more information
     let subject_names = certificate.subject_name();
@@ -702,6 +1787,24 @@ fn clear_unique_file_list() -> std::result::Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+// Removes all temporary files, regardless of whether they are still in use
+fn remove_all_unique_files() {
+    let mut file_map = match UNIQUE_FILE_LIST.lock() {
+        Ok(val) => val,
+        Err(_) => return
+    };
+
+    for file_id in file_map.keys() {
+        let file_name = format!("{}/{}", TEMP_DIR, file_id);
+        match fs::remove_file(&file_name) {
+            Ok(_) => (),
+            Err(_) => ()
+        };
+    }
+
+    file_map.clear();
+}
+
 fn cache_files(tree: &UrlTree) {
     let mut all_nodes = Vec::new();
 
@@ -713,18 +1816,22 @@ fn cache_files(tree: &UrlTree) {
 
     for node in all_nodes {
         if let FileType::Dynamic(dyn_obj) = &node.data.as_ref().unwrap().meta_data { // The data is always dynamic object
-            let data = match load_dynamic_content(dyn_obj, &None, &None) {
-                Ok(val) => val,
+            let data = match load_dynamic_content(dyn_obj, None, None, tree.settings.log) {
+                Ok((val, _)) => val,
                 Err(err) => {
                     log(&format!("Error: Failed to cache file. {}", err));
                     continue;
                 }
             };
 
-            // File pathes are hashes to uniquely identify
+            // File pathes are hashes to uniquely identify, the data is renamed into place
+            // so that requests and shutdowns never see half-written files
             let file_path = format!("{}/{}", &*CACHE_DIR, get_hash(dyn_obj));
+            let partial_file_path = format!("{}.partial", &file_path);
 
-            fs::write(file_path, data).and_then(|_| Ok(())).unwrap();
+            if let Err(err) = fs::write(&partial_file_path, data).and_then(|_| fs::rename(&partial_file_path, &file_path)) {
+                log(&format!("Error: Failed to cache file. {}", err));
+            }
         }
     }
 }
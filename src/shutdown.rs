@@ -0,0 +1,167 @@
+use std::collections::HashMap;
+use std::io;
+use std::process::{ Child, ExitStatus };
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicBool, AtomicUsize, Ordering };
+use std::thread;
+use std::time::{ Duration, Instant };
+
+const DRAIN_POLL_TIME: u64 = 100; // How often to check for active clients while draining (milliseconds)
+#[cfg(not(unix))]
+const WAIT_POLL_TIME: u64 = 10; // How often to check whether a process has exited (milliseconds)
+
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+static ACTIVE_CLIENTS: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    static ref ACTIVE_PROCESSES: Mutex<HashMap<u32, Arc<Mutex<Child>>>> = Mutex::new(HashMap::new());
+}
+
+// Keeps a client counted as active while it is alive
+pub struct ClientGuard;
+
+impl ClientGuard {
+    pub fn new() -> Self {
+        ACTIVE_CLIENTS.fetch_add(1, Ordering::SeqCst);
+        ClientGuard
+    }
+}
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        ACTIVE_CLIENTS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Keeps a dynamic object's process counted as active while it is alive. The process is kept
+// reachable, so that it can be killed where there are no process groups to signal
+pub struct ProcessGuard {
+    pid: u32
+}
+
+impl ProcessGuard {
+    pub fn new(pid: u32, process: &Arc<Mutex<Child>>) -> Self {
+        if let Ok(mut processes) = ACTIVE_PROCESSES.lock() {
+            processes.insert(pid, process.clone());
+        }
+
+        ProcessGuard {
+            pid
+        }
+    }
+}
+
+impl Drop for ProcessGuard {
+    fn drop(&mut self) {
+        if let Ok(mut processes) = ACTIVE_PROCESSES.lock() {
+            processes.remove(&self.pid);
+        }
+    }
+}
+
+pub fn is_shutting_down() -> bool {
+    SHUTTING_DOWN.load(Ordering::SeqCst)
+}
+
+pub fn start_shutdown() {
+    SHUTTING_DOWN.store(true, Ordering::SeqCst);
+}
+
+// Waits until all clients and processes are done or the grace period is over.
+// Returns whether everything finished in time
+pub fn drain(grace_period: Duration) -> bool {
+    let start_time = Instant::now();
+
+    loop {
+        let processes = match ACTIVE_PROCESSES.lock() {
+            Ok(val) => val.len(),
+            Err(_) => 0
+        };
+        if ACTIVE_CLIENTS.load(Ordering::SeqCst) == 0 && processes == 0 {
+            return true;
+        }
+        if start_time.elapsed() >= grace_period {
+            return false;
+        }
+
+        thread::sleep(Duration::from_millis(DRAIN_POLL_TIME));
+    }
+}
+
+// Kills all processes that are still running
+pub fn kill_processes() {
+    let processes: Vec<u32> = match ACTIVE_PROCESSES.lock() {
+        Ok(val) => val.keys().cloned().collect(),
+        Err(_) => return
+    };
+
+    for pid in processes {
+        kill_process(pid);
+    }
+}
+
+// Waits for a process to exit. Processes are killed by their pid on unix, so the lock can be held
+// while waiting. Elsewhere killing needs the lock, so the process is polled instead
+#[cfg(unix)]
+pub fn wait_process(process: &Mutex<Child>) -> io::Result<ExitStatus> {
+    match process.lock() {
+        Ok(mut val) => val.wait(),
+        Err(poisoned) => poisoned.into_inner().wait()
+    }
+}
+
+#[cfg(not(unix))]
+pub fn wait_process(process: &Mutex<Child>) -> io::Result<ExitStatus> {
+    loop {
+        let status = match process.lock() {
+            Ok(mut val) => val.try_wait(),
+            Err(poisoned) => poisoned.into_inner().try_wait()
+        };
+        match status {
+            Ok(None) => thread::sleep(Duration::from_millis(WAIT_POLL_TIME)),
+            Ok(Some(val)) => return Ok(val),
+            Err(err) => return Err(err)
+        }
+    }
+}
+
+// Processes of dynamic objects lead their own process group, so their children are signaled as well
+#[cfg(unix)]
+pub fn kill_process(pid: u32) {
+    unsafe {
+        libc::kill(-(pid as libc::pid_t), libc::SIGKILL);
+    }
+}
+
+// Without process groups only the process itself can be killed
+#[cfg(not(unix))]
+pub fn kill_process(pid: u32) {
+    let process = match ACTIVE_PROCESSES.lock() {
+        Ok(val) => val.get(&pid).cloned(),
+        Err(_) => return
+    };
+
+    if let Some(process) = process {
+        let result = match process.lock() {
+            Ok(mut val) => val.kill(),
+            Err(poisoned) => poisoned.into_inner().kill()
+        };
+        match result {
+            Ok(_) => (),
+            Err(_) => ()
+        };
+    }
+}
+
+#[cfg(unix)]
+pub fn terminate_process(pid: u32) {
+    unsafe {
+        libc::kill(-(pid as libc::pid_t), libc::SIGTERM);
+    }
+}
+
+// There is no way to ask a process to exit, so it is killed right away
+#[cfg(not(unix))]
+pub fn terminate_process(pid: u32) {
+    kill_process(pid);
+}
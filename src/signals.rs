@@ -0,0 +1,47 @@
+use std::sync::atomic::{ AtomicBool, Ordering };
+use crate::{ log, shutdown };
+
+static RELOAD_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+// Starts shutting down on SIGTERM or SIGINT (a second signal exits immediately)
+// and requests a reload of the url tree on SIGHUP
+#[cfg(unix)]
+pub fn listen() {
+    use std::thread;
+    use signal_hook::consts::{ SIGTERM, SIGINT, SIGHUP };
+    use signal_hook::iterator::Signals;
+
+    let mut signals = match Signals::new(&[SIGTERM, SIGINT, SIGHUP]) {
+        Ok(val) => val,
+        Err(err) => {
+            log(&format!("Warning: Failed to register signal handlers. {}", err));
+            return;
+        }
+    };
+
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            if signal == SIGHUP {
+                log("Info: Received SIGHUP, reloading url tree");
+                RELOAD_REQUESTED.store(true, Ordering::SeqCst);
+                continue;
+            }
+
+            if shutdown::is_shutting_down() {
+                log(&format!("Info: Received signal {} again, exiting immediately", signal));
+                std::process::exit(1);
+            }
+
+            log(&format!("Info: Received signal {}, shutting down", signal));
+            shutdown::start_shutdown();
+        }
+    });
+}
+
+#[cfg(not(unix))]
+pub fn listen() {}
+
+// Returns whether a reload was requested since the last call
+pub fn take_reload_request() -> bool {
+    RELOAD_REQUESTED.swap(false, Ordering::SeqCst)
+}
@@ -0,0 +1,636 @@
+use std::fs::{ self, File };
+use std::io::{ self, Read };
+use std::collections::HashMap;
+use std::process;
+use std::cmp::Ordering;
+use std::net::{ IpAddr, Ipv4Addr, Ipv6Addr };
+use openssl::ssl::{ SslAcceptor, SslAcceptorBuilder, SslContext, SslMethod, SslVerifyMode, NameType, SniError };
+use openssl::pkcs12::Pkcs12;
+use openssl::pkey::{ PKey, PKeyRef, Private };
+use openssl::rsa::Rsa;
+use openssl::ec::{ EcGroup, EcKey };
+use openssl::bn::{ BigNum, MsbOption };
+use openssl::asn1::Asn1Time;
+use openssl::hash::MessageDigest;
+use openssl::x509::{ X509, X509Builder, X509NameBuilder, X509Ref, X509NameRef, X509StoreContext, X509VerifyResult };
+use openssl::x509::extension::{ BasicConstraints, ExtendedKeyUsage, KeyUsage, SubjectAlternativeName };
+use openssl::x509::store::X509StoreBuilder;
+use openssl::stack::Stack;
+use openssl::nid::Nid;
+use openssl::error::ErrorStack;
+use chrono::NaiveDateTime;
+use crate::log;
+use crate::url_tree::ServerSettings;
+
+// Verification errors meaning that the issuer of a certificate could not be found
+const UNABLE_TO_GET_ISSUER_CERT: i32 = 2;
+const UNABLE_TO_GET_ISSUER_CERT_LOCALLY: i32 = 20;
+const KEYUSAGE_NO_CERTSIGN: i32 = 32;
+
+// Where a certificate and its private key are loaded from
+enum IdentitySource<'a> {
+    Pkcs12 { profile: &'a str, password: &'a str },
+    Pem { cert: &'a str, key: &'a str, chain: Option<&'a str> }
+}
+
+impl<'a> IdentitySource<'a> {
+    fn path(&self) -> &'a str {
+        match self {
+            IdentitySource::Pkcs12 { profile, .. } => profile,
+            IdentitySource::Pem { cert, .. } => cert
+        }
+    }
+}
+
+struct Identity {
+    cert: X509,
+    pkey: PKey<Private>,
+    chain: Vec<X509>
+}
+
+// Creates the acceptor used by all listeners. Clients asking for a domain with its own
+// certificate (through SNI) are given that one, all others get the default certificate
+pub fn build_acceptor(settings: &ServerSettings) -> Result<SslAcceptor, String> {
+    let mut domain_contexts: HashMap<String, SslContext> = HashMap::new();
+    for (domain, source) in get_domain_sources(settings) {
+        let identity = load_identity(&source)?;
+        validate_chain(&identity, source.path(), settings.never_exit)?;
+        let context = new_acceptor(identity)?.build().into_context();
+        if domain_contexts.insert(domain.to_lowercase(), context).is_some() {
+            eprintln!("Warning: Multiple certificates for the domain \"{}\", using the last one", domain);
+        }
+    }
+
+    let source = get_default_source(settings);
+    let identity = load_identity(&source)?;
+    validate_chain(&identity, source.path(), settings.never_exit)?;
+    let mut acceptor = new_acceptor(identity)?;
+    if !domain_contexts.is_empty() {
+        acceptor.set_servername_callback(move |ssl, _| {
+            let server_name = match ssl.servername(NameType::HOST_NAME) {
+                Some(val) => val.to_lowercase(),
+                None => return Ok(()) // Client did not send a server name, use the default
+            };
+
+            if let Some(context) = domain_contexts.get(&server_name) {
+                match ssl.set_ssl_context(context) {
+                    Ok(_) => (),
+                    Err(_) => return Err(SniError::ALERT_FATAL)
+                };
+            }
+            Ok(())
+        });
+    }
+
+    Ok(acceptor.build())
+}
+
+// Returns the fingerprint, names, expiry and chain state of every configured certificate
+pub fn get_certificate_info(settings: &ServerSettings) -> String {
+    let mut info = String::new();
+    for (name, source) in get_all_sources(settings) {
+        info += &format!("{} ({})\n", name, source.path());
+        let identity = match load_identity(&source) {
+            Ok(val) => val,
+            Err(err) => {
+                info += &format!("    Error: {}\n\n", err);
+                continue;
+            }
+        };
+        let cert = &identity.cert;
+
+        let names = get_alt_names(cert);
+        let remaining = match get_days_until_expiry(cert) {
+            Some(days) if days < 0 => String::from("expired"),
+            Some(days) => format!("{} days left", days),
+            None => String::from("unknown")
+        };
+        let chain_state = match check_chain(&identity) {
+            Ok(_) => String::from("valid"),
+            Err(err) => format!("invalid. {}", err)
+        };
+
+        info += &format!("    Subject:     {}\n", get_name(cert.subject_name()));
+        info += &format!("    Names:       {}\n", if names.is_empty() { String::from("none") } else { names.join(", ") });
+        info += &format!("    Fingerprint: {}\n", get_fingerprint(cert));
+        info += &format!("    Valid from:  {}\n", cert.not_before());
+        info += &format!("    Expires:     {} ({})\n", cert.not_after(), remaining);
+        info += &format!("    Chain:       {} certificate(s), {}\n\n", identity.chain.len(), chain_state);
+    }
+
+    info
+}
+
+// Logs a warning for every certificate that expires within "certificate_warning_days". If
+// "renew_self_signed" is set, self-signed certificates are renewed instead, keeping their
+// private key so that clients which trust the key keep trusting the server. Returns whether
+// a certificate was renewed
+pub fn check_expiry(settings: &ServerSettings) -> bool {
+    let mut renewed = false;
+    for (_, source) in get_all_sources(settings) {
+        let identity = match load_identity(&source) {
+            Ok(val) => val,
+            Err(err) => {
+                log(&format!("Error: Failed to check the expiry of a certificate. {}", err));
+                continue;
+            }
+        };
+        let cert_src = source.path();
+
+        let days = match get_days_until_expiry(&identity.cert) {
+            Some(val) => val,
+            None => continue
+        };
+        if days > settings.certificate_warning_days as i32 {
+            continue;
+        }
+
+        let self_signed = identity.chain.is_empty() && is_self_signed(&identity.cert);
+        if settings.renew_self_signed && self_signed {
+            match renew_self_signed(&source, &identity, settings.certificate_warning_days + 1) {
+                Ok(cert) => {
+                    log(&format!("Info: Renewed the self-signed certificate \"{}\", it now expires on {}", cert_src, cert.not_after()));
+                    renewed = true;
+                    continue;
+                },
+                Err(err) => log(&format!("Error: Failed to renew the certificate \"{}\". {}", cert_src, err))
+            };
+        }
+
+        if days < 0 {
+            log(&format!("Warning: The certificate \"{}\" has expired on {}", cert_src, identity.cert.not_after()));
+        }
+        else {
+            log(&format!("Warning: The certificate \"{}\" expires in {} day(s) on {}", cert_src, days, identity.cert.not_after()));
+        }
+    }
+
+    renewed
+}
+
+// Replaces the certificate with one for the same names and key, valid as long as the old one
+// was, but at least for the given amount of days
+fn renew_self_signed(source: &IdentitySource, identity: &Identity, min_days: u32) -> Result<X509, String> {
+    let old_cert = &identity.cert;
+    let subject = get_name(old_cert.subject_name());
+    let mut names = vec![subject.clone()];
+    names.extend(get_alt_names(old_cert).into_iter().filter(|name| name != &subject));
+
+    let days = match old_cert.not_before().diff(old_cert.not_after()) {
+        Ok(diff) if diff.days > 0 => (diff.days as u32).max(min_days),
+        _ => min_days
+    };
+    let cert = create_self_signed(&identity.pkey, &names, days).map_err(|err| err.to_string())?;
+
+    let (path, data) = match *source {
+        IdentitySource::Pkcs12 { profile, password } => {
+            let pkcs12 = Pkcs12::builder().build(password, &subject, &identity.pkey, &cert).map_err(|err| err.to_string())?;
+            (profile, pkcs12.to_der().map_err(|err| err.to_string())?)
+        },
+        IdentitySource::Pem { cert: cert_src, .. } => (cert_src, cert.to_pem().map_err(|err| err.to_string())?)
+    };
+    replace_file(path, &data).map_err(|err| err.to_string())?;
+
+    Ok(cert)
+}
+
+// Writes the new file next to the old one before replacing it, so that the old file stays
+// intact on failure. The permissions of the old file are kept
+fn replace_file(path: &str, data: &[u8]) -> io::Result<()> {
+    let partial_path = format!("{}.partial", path);
+    let permissions = fs::metadata(path)?.permissions();
+    fs::write(&partial_path, data)?;
+    fs::set_permissions(&partial_path, permissions)?;
+    fs::rename(&partial_path, path)
+}
+
+// Creates a new private key of the given type
+pub fn generate_key(key_type: &str) -> Result<PKey<Private>, ErrorStack> {
+    match key_type {
+        "rsa2048" => PKey::from_rsa(Rsa::generate(2048)?),
+        "ecdsa-p256" => PKey::from_ec_key(EcKey::generate(EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?.as_ref())?),
+        "ecdsa-p384" => PKey::from_ec_key(EcKey::generate(EcGroup::from_curve_name(Nid::SECP384R1)?.as_ref())?),
+        _ => PKey::from_rsa(Rsa::generate(4096)?)
+    }
+}
+
+// Creates a self-signed certificate for the domains, the first domain is used as the subject
+pub fn create_self_signed(pkey: &PKeyRef<Private>, domains: &[String], days: u32) -> Result<X509, ErrorStack> {
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_nid(Nid::COMMONNAME, &domains[0])?;
+    let name = name.build();
+
+    let mut serial = BigNum::new()?;
+    serial.rand(127, MsbOption::MAYBE_ZERO, false)?;
+
+    let mut cert = X509Builder::new()?;
+    cert.set_version(2)?; // X509v3
+    cert.set_serial_number(serial.to_asn1_integer()?.as_ref())?;
+    cert.set_subject_name(&name)?;
+    cert.set_issuer_name(&name)?;
+    cert.set_pubkey(pkey)?;
+    cert.set_not_before(Asn1Time::days_from_now(0)?.as_ref())?;
+    cert.set_not_after(Asn1Time::days_from_now(days)?.as_ref())?;
+
+    let mut alt_names = SubjectAlternativeName::new();
+    for domain in domains {
+        match domain.parse::<IpAddr>() {
+            Ok(_) => alt_names.ip(domain),
+            Err(_) => alt_names.dns(domain)
+        };
+    }
+    let alt_names = alt_names.build(&cert.x509v3_context(None, None))?;
+    cert.append_extension(alt_names)?;
+    cert.append_extension(BasicConstraints::new().critical().build()?)?;
+    cert.append_extension(KeyUsage::new().critical().digital_signature().key_encipherment().build()?)?;
+    cert.append_extension(ExtendedKeyUsage::new().server_auth().build()?)?;
+
+    cert.sign(pkey, MessageDigest::sha256())?;
+    Ok(cert.build())
+}
+
+// Checks the validity window of a client certificate and, if it is self-signed, its signature.
+// The returned error explains the problem to the client
+pub fn validate_client_certificate(certificate: &X509Ref) -> Result<(), String> {
+    let now = Asn1Time::days_from_now(0).map_err(|_| String::from("Certificate could not be checked"))?;
+
+    match certificate.not_before().compare(&now) {
+        Ok(Ordering::Greater) => return Err(format!("Certificate is not valid yet, it is valid from {}", certificate.not_before())),
+        Ok(_) => (),
+        Err(_) => return Err(String::from("Certificate has an invalid start of its validity"))
+    };
+    match certificate.not_after().compare(&now) {
+        Ok(Ordering::Less) => return Err(format!("Certificate has expired on {}", certificate.not_after())),
+        Ok(_) => (),
+        Err(_) => return Err(String::from("Certificate has an invalid end of its validity"))
+    };
+
+    // Certificates issued by someone else can not be checked without the issuer
+    let result = certificate.issued(certificate);
+    if result == X509VerifyResult::OK || result.as_raw() == KEYUSAGE_NO_CERTSIGN {
+        let valid_signature = match certificate.public_key() {
+            Ok(key) => certificate.verify(&key).unwrap_or(false),
+            Err(_) => false
+        };
+        if !valid_signature {
+            return Err(String::from("Certificate is self-signed, but its signature does not match its key"));
+        }
+    }
+
+    Ok(())
+}
+
+// Returns the SHA256 fingerprint of the certificate as uppercase hex
+pub fn get_fingerprint(certificate: &X509Ref) -> String {
+    match certificate.digest(MessageDigest::sha256()) {
+        Ok(digest) => {
+            digest.as_ref()
+                .iter()
+                .map(|val| format!("{:02X}", val))
+                .collect()
+        },
+        Err(_) => String::from("Error")
+    }
+}
+
+// Returns the subject of the certificate as a distinguished name (e.g. CN=name,O=organization)
+pub fn get_subject(certificate: &X509Ref) -> String {
+    certificate.subject_name()
+        .entries()
+        .filter_map(|entry| {
+            let key = entry.object().nid().short_name().ok()?;
+            let value = entry.data().as_utf8().ok()?;
+            let value: String = value.chars()
+                .flat_map(|c| match c {
+                    ',' | '+' | '"' | '\\' | '<' | '>' | ';' | '=' => vec!['\\', c],
+                    _ => vec![c]
+                })
+                .collect();
+
+            Some(format!("{}={}", key, value))
+        })
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+// Returns the end of the validity of the certificate as an ISO 8601 date in UTC
+pub fn get_expiry_date(certificate: &X509Ref) -> Option<String> {
+    let epoch = Asn1Time::from_unix(0).ok()?;
+    let diff = epoch.diff(certificate.not_after()).ok()?;
+    let timestamp = diff.days as i64 * 86400 + diff.secs as i64;
+
+    NaiveDateTime::from_timestamp_opt(timestamp, 0)
+        .map(|date| date.format("%Y-%m-%dT%H:%M:%SZ").to_string())
+}
+
+// Returns the amount of days until the certificate expires (started days are counted),
+// negative if it has expired
+pub fn get_days_until_expiry(certificate: &X509Ref) -> Option<i32> {
+    let now = Asn1Time::days_from_now(0).ok()?;
+    let diff = now.diff(certificate.not_after()).ok()?;
+
+    if diff.secs > 0 {
+        Some(diff.days + 1)
+    }
+    else if diff.secs < 0 {
+        Some(diff.days - 1)
+    }
+    else {
+        Some(diff.days)
+    }
+}
+
+fn get_alt_names(certificate: &X509Ref) -> Vec<String> {
+    let alt_names = match certificate.subject_alt_names() {
+        Some(val) => val,
+        None => return Vec::new()
+    };
+
+    alt_names.iter()
+        .filter_map(|name| {
+            if let Some(dns) = name.dnsname() {
+                Some(dns.to_string())
+            }
+            else {
+                name.ipaddress().and_then(format_ip)
+            }
+        })
+        .collect()
+}
+
+fn format_ip(bytes: &[u8]) -> Option<String> {
+    match bytes.len() {
+        4 => Some(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]).to_string()),
+        16 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(bytes);
+            Some(Ipv6Addr::from(octets).to_string())
+        },
+        _ => None
+    }
+}
+
+fn get_all_sources(settings: &ServerSettings) -> Vec<(String, IdentitySource<'_>)> {
+    let mut sources = vec![(String::from("Default"), get_default_source(settings))];
+    for (domain, source) in get_domain_sources(settings) {
+        sources.push((format!("Domain {}", domain), source));
+    }
+
+    sources
+}
+
+fn get_default_source(settings: &ServerSettings) -> IdentitySource<'_> {
+    get_identity_source(
+        Some(&settings.tls_profile),
+        &settings.profile_password,
+        &settings.tls_cert,
+        &settings.tls_key,
+        &settings.tls_chain,
+        "the default certificate"
+    )
+}
+
+fn get_domain_sources(settings: &ServerSettings) -> Vec<(&str, IdentitySource<'_>)> {
+    settings.certificates.iter()
+        .map(|domain_cert| {
+            let source = get_identity_source(
+                domain_cert.tls_profile.as_deref(),
+                &domain_cert.profile_password,
+                &domain_cert.tls_cert,
+                &domain_cert.tls_key,
+                &domain_cert.tls_chain,
+                &domain_cert.domain
+            );
+            (domain_cert.domain.as_str(), source)
+        })
+        .collect()
+}
+
+// Pem files are used if "tls_cert" or "tls_key" is set, otherwise the pkcs12 profile
+fn get_identity_source<'a>(
+    profile: Option<&'a str>,
+    password: &'a str,
+    cert: &'a Option<String>,
+    key: &'a Option<String>,
+    chain: &'a Option<String>,
+    name: &str
+) -> IdentitySource<'a> {
+    match (cert, key) {
+        (Some(cert), Some(key)) => IdentitySource::Pem { cert, key, chain: chain.as_deref() },
+        (Some(_), None) | (None, Some(_)) => exit_with_error(format!(
+            "Critical Error: Both \"tls_cert\" and \"tls_key\" must be set to use pem files for {}", name
+        )),
+        (None, None) => match profile {
+            Some(profile) => IdentitySource::Pkcs12 { profile, password },
+            None => exit_with_error(format!(
+                "Critical Error: Either \"tls_profile\" or \"tls_cert\" and \"tls_key\" must be set for {}", name
+            ))
+        }
+    }
+}
+
+fn load_identity(source: &IdentitySource) -> Result<Identity, String> {
+    let identity = match *source {
+        IdentitySource::Pkcs12 { profile, password } => load_pkcs12_identity(profile, password)?,
+        IdentitySource::Pem { cert, key, chain } => load_pem_identity(cert, key, chain)?
+    };
+    let cert_src = source.path();
+
+    // Catch mismatched files here, openssl only reports a generic error once the key is set
+    let public_key = identity.cert.public_key()
+        .map_err(|_| format!("Failed to read the public key of \"{}\"", cert_src))?;
+    if !identity.pkey.public_eq(&public_key) {
+        return Err(format!("The private key does not match the certificate \"{}\"", cert_src));
+    }
+
+    Ok(identity)
+}
+
+fn validate_chain(identity: &Identity, cert_src: &str, never_exit: bool) -> Result<(), String> {
+    // Clients that verify the chain fail on missing or misplaced certificates
+    if let Err(err) = check_chain(&identity) {
+        if never_exit {
+            log(&format!("Warning: The certificate chain of \"{}\" is invalid. {}", cert_src, err));
+        }
+        else {
+            return Err(format!("The certificate chain of \"{}\" is invalid. {}", cert_src, err));
+        }
+    }
+
+    Ok(())
+}
+
+// Checks that every certificate in the chain issued the one before it and that the last
+// one is either a root or was issued by a root in the trust store of the system
+fn check_chain(identity: &Identity) -> Result<(), String> {
+    let mut child: &X509Ref = &identity.cert;
+    for (i, chain_cert) in identity.chain.iter().enumerate() {
+        if !is_issuer(chain_cert, child) {
+            return Err(format!(
+                "Chain certificate {} (\"{}\") is not the issuer of \"{}\" (\"{}\"), the chain is incomplete or out of order",
+                i + 1, get_name(chain_cert.subject_name()), get_name(child.subject_name()), get_name(child.issuer_name())
+            ));
+        }
+        child = chain_cert;
+    }
+
+    // Self-signed certificates (the usual case for gemini) have nothing left to check
+    if is_self_signed(child) {
+        return Ok(());
+    }
+
+    match verify_with_system_roots(identity) {
+        Ok(result) if result.as_raw() == UNABLE_TO_GET_ISSUER_CERT || result.as_raw() == UNABLE_TO_GET_ISSUER_CERT_LOCALLY => {
+            Err(format!(
+                "The chain is incomplete, the issuer of \"{}\" (\"{}\") is missing",
+                get_name(child.subject_name()), get_name(child.issuer_name())
+            ))
+        },
+        _ => Ok(()) // Other problems (e.g. expiry) are not caused by the chain
+    }
+}
+
+fn verify_with_system_roots(identity: &Identity) -> Result<X509VerifyResult, ErrorStack> {
+    let mut store = X509StoreBuilder::new()?;
+    store.set_default_paths()?;
+    let store = store.build();
+
+    let mut chain = Stack::new()?;
+    for chain_cert in &identity.chain {
+        chain.push(chain_cert.clone())?;
+    }
+
+    let mut context = X509StoreContext::new()?;
+    context.init(&store, &identity.cert, &chain, |context| {
+        context.verify_cert()?;
+        Ok(context.error())
+    })
+}
+
+// Orders certificates from the one issuing the given certificate up to the root,
+// certificates that are not part of the chain are left at the end
+fn order_chain(cert: &X509Ref, mut certs: Vec<X509>) -> Vec<X509> {
+    let mut chain: Vec<X509> = Vec::new();
+    loop {
+        let child = match chain.last() {
+            Some(val) => val.as_ref(),
+            None => cert
+        };
+        if is_self_signed(child) {
+            break;
+        }
+
+        let position = match certs.iter().position(|issuer| is_issuer(issuer, child)) {
+            Some(val) => val,
+            None => break
+        };
+        chain.push(certs.remove(position));
+    }
+
+    chain.append(&mut certs);
+    chain
+}
+
+// Self-signed leaf certificates often lack the key usage for signing certificates, which is ignored here
+fn is_self_signed(certificate: &X509Ref) -> bool {
+    let result = certificate.issued(certificate);
+    if result != X509VerifyResult::OK && result.as_raw() != KEYUSAGE_NO_CERTSIGN {
+        return false;
+    }
+
+    match certificate.public_key() {
+        Ok(key) => certificate.verify(&key).unwrap_or(false),
+        Err(_) => false
+    }
+}
+
+fn is_issuer(issuer: &X509Ref, subject: &X509Ref) -> bool {
+    if issuer.issued(subject) != X509VerifyResult::OK {
+        return false;
+    }
+
+    match issuer.public_key() {
+        Ok(key) => subject.verify(&key).unwrap_or(false),
+        Err(_) => false
+    }
+}
+
+fn get_name(name: &X509NameRef) -> String {
+    match name.entries_by_nid(Nid::COMMONNAME).next().and_then(|entry| entry.data().as_utf8().ok()) {
+        Some(val) => val.to_string(),
+        None => String::from("unnamed")
+    }
+}
+
+fn load_pkcs12_identity(cert_src: &str, cert_passwd: &str) -> Result<Identity, String> {
+    let pfx_data = read_file(cert_src)?;
+    let pkcs12 = Pkcs12::from_der(&pfx_data)
+        .map_err(|_| format!("Failed to parse pfx file \"{}\" (bad certificate)", cert_src))?;
+    let identity = pkcs12.parse(cert_passwd)
+        .map_err(|_| format!("Failed to create identity from \"{}\" (incorrect password)", cert_src))?;
+
+    // The order of the certificates in a profile is not meaningful, so it is restored here
+    let chain: Vec<X509> = match identity.chain {
+        Some(val) => val.into_iter().collect(),
+        None => Vec::new()
+    };
+    let chain = order_chain(&identity.cert, chain);
+
+    Ok(Identity {
+        cert: identity.cert,
+        pkey: identity.pkey,
+        chain
+    })
+}
+
+fn load_pem_identity(cert_src: &str, key_src: &str, chain_src: Option<&str>) -> Result<Identity, String> {
+    // Any certificates after the first one in the certificate file are part of the chain
+    let mut certs = X509::stack_from_pem(&read_file(cert_src)?)
+        .map_err(|_| format!("Failed to parse certificate \"{}\" (bad pem file)", cert_src))?;
+    if certs.is_empty() {
+        return Err(format!("No certificate found in \"{}\"", cert_src));
+    }
+    let cert = certs.remove(0);
+    let mut chain = certs;
+
+    if let Some(chain_src) = chain_src {
+        let mut chain_certs = X509::stack_from_pem(&read_file(chain_src)?)
+            .map_err(|_| format!("Failed to parse certificate chain \"{}\" (bad pem file)", chain_src))?;
+        chain.append(&mut chain_certs);
+    }
+
+    let pkey = PKey::private_key_from_pem(&read_file(key_src)?)
+        .map_err(|_| format!("Failed to parse private key \"{}\" (bad or encrypted pem file)", key_src))?;
+
+    Ok(Identity {
+        cert,
+        pkey,
+        chain
+    })
+}
+
+fn read_file(path: &str) -> Result<Vec<u8>, String> {
+    let mut file = File::open(path).map_err(|_| format!("Failed to open certificate \"{}\"", path))?;
+    let mut data: Vec<u8> = vec![];
+    file.read_to_end(&mut data).map_err(|_| format!("Failed to read certificate \"{}\"", path))?;
+
+    Ok(data)
+}
+
+fn new_acceptor(identity: Identity) -> Result<SslAcceptorBuilder, String> {
+    let cert_init_error = |_| String::from("Failed to initialize acceptor");
+    let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls()).map_err(cert_init_error)?;
+    acceptor.set_certificate(&identity.cert).map_err(cert_init_error)?;
+    acceptor.set_private_key(&identity.pkey).map_err(cert_init_error)?;
+    for chain_cert in identity.chain {
+        acceptor.add_extra_chain_cert(chain_cert).map_err(cert_init_error)?;
+    }
+    acceptor.set_verify_callback(SslVerifyMode::PEER, |_, _| true);
+
+    Ok(acceptor)
+}
+
+fn exit_with_error(message: String) -> ! {
+    eprintln!("{}", message);
+    process::exit(101)
+}
@@ -1,20 +1,99 @@
 use std::io::Read;
 use std::fs::{ self, OpenOptions };
-use std::collections::HashMap;
+use std::collections::{ HashMap, hash_map::DefaultHasher };
+use std::hash::{ Hash, Hasher };
+use std::panic;
+use std::process;
 use serde_json;
-use crate::{ log, expect_pretty };
+use crate::log;
+use crate::protocol;
 pub use structs::*;
 
 mod structs;
 
+const SETTINGS_FILE: &str = "server_settings.json";
+
 pub fn get_url_tree() -> UrlTree {
+    let exit_on_err = |err: String| -> ! {
+        eprintln!("{}", err);
+        process::exit(101)
+    };
+
     // Read top level settings
-    let settings = read_settings();
+    let settings = read_settings().unwrap_or_else(|err| exit_on_err(err));
 
     // Read all lower level config
     let root_path = Path::from_str(&settings.root);
-    let mut all_config = read_all_config_files(&settings.config_files, &root_path);
+    let all_config = read_all_config_files(&settings.config_files, &root_path).unwrap_or_else(|err| exit_on_err(err));
+
+    build_url_tree(settings, all_config)
+}
+
+// Same as get_url_tree, but returns errors instead of exiting or panicking
+pub fn try_get_url_tree() -> Result<UrlTree, String> {
+    let settings = read_settings()?;
+    let root_path = Path::from_str(&settings.root);
+    let all_config = read_all_config_files(&settings.config_files, &root_path)?;
+
+    match panic::catch_unwind(move || build_url_tree(settings, all_config)) {
+        Ok(val) => Ok(val),
+        Err(payload) => {
+            if let Some(message) = payload.downcast_ref::<String>() {
+                Err(message.clone())
+            }
+            else if let Some(message) = payload.downcast_ref::<&str>() {
+                Err(message.to_string())
+            }
+            else {
+                Err(String::from("Error: Failed to build the url tree"))
+            }
+        }
+    }
+}
+
+// Returns a value which changes whenever the settings or a file under the root are changed
+pub fn get_file_state(settings: &ServerSettings) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hash_file_state(SETTINGS_FILE, &mut hasher, true);
+    hash_file_state(&settings.root, &mut hasher, true);
+
+    hasher.finish()
+}
 
+// Symlinks below the given path are not followed, so that links to parent directories can not cause endless recursion
+fn hash_file_state(path: &str, hasher: &mut DefaultHasher, follow_links: bool) {
+    let meta_data = match follow_links {
+        true => fs::metadata(path),
+        false => fs::symlink_metadata(path)
+    };
+    let meta_data = match meta_data {
+        Ok(val) => val,
+        Err(_) => return
+    };
+
+    path.hash(hasher);
+    if let Ok(time) = meta_data.modified() {
+        time.hash(hasher);
+    }
+    meta_data.len().hash(hasher);
+
+    if meta_data.is_dir() {
+        let mut entries: Vec<String> = match fs::read_dir(path) {
+            Ok(val) => val
+                .filter_map(|entry| entry.ok())
+                .filter_map(|entry| entry.path().to_str().map(String::from))
+                .collect(),
+            Err(_) => return
+        };
+        entries.sort(); // Order of entries is not guaranteed
+
+        for entry in entries {
+            hash_file_state(&entry, hasher, false);
+        }
+    }
+}
+
+fn build_url_tree(settings: ServerSettings, mut all_config: Vec<ConfigWithPath>) -> UrlTree {
     // If one level has two config files, either show warning or panic
     let len = all_config.len();
     let mut i = 0;
@@ -95,37 +174,54 @@ pub fn get_url_tree() -> UrlTree {
         organized_trees.push(root_node);
     }
 
+    // Get proxies, uploads and access restrictions
+    let proxies = get_proxies(&sorted_config_list, &settings);
+    let uploads = get_uploads(&sorted_config_list, &settings);
+    let access = get_access(&sorted_config_list, &settings);
+
+    // Remember which files configure the server, so that uploads can not replace them
+    let config_files = sorted_config_list.iter()
+        .map(|config| config.path.original.as_str())
+        .chain(std::iter::once(SETTINGS_FILE))
+        .filter_map(|path| fs::canonicalize(path).ok())
+        .collect();
+
     UrlTree::new(
         settings,
-        organized_trees
+        organized_trees,
+        proxies,
+        uploads,
+        access,
+        config_files
     )
 }
 
-fn read_settings() -> ServerSettings {
+fn read_settings() -> Result<ServerSettings, String> {
     let settings_file = OpenOptions::new()
         .read(true)
-        .open("server_settings.json");
-    let mut settings_file = expect_pretty(settings_file, "Critical Error: Could not open settings file");
+        .open(SETTINGS_FILE);
+    let mut settings_file = settings_file.map_err(|_| String::from("Critical Error: Could not open settings file"))?;
 
     let mut settings_json = String::new();
-    expect_pretty(settings_file.read_to_string(&mut settings_json), "Critical Error: Could not read settings file");
+    settings_file.read_to_string(&mut settings_json).map_err(|_| String::from("Critical Error: Could not read settings file"))?;
 
     let settings: Result<ServerSettings, _> = serde_json::from_str(&settings_json);
-    let settings = expect_pretty(settings, "Critical Error: Invalid settings file");
+    let mut settings = settings.map_err(|_| String::from("Critical Error: Invalid settings file"))?;
+    settings.domain = settings.domain.to_lowercase(); // Hosts of requests are compared in lowercase
     
-    settings
+    Ok(settings)
 }
 
-fn read_all_config_files(config_filenames: &Vec<String>, parent_path: &Path) -> Vec<ConfigWithPath> {
+fn read_all_config_files(config_filenames: &Vec<String>, parent_path: &Path) -> Result<Vec<ConfigWithPath>, String> {
     let mut config_list: Vec<ConfigWithPath> = Vec::new();
     
     for filename in config_filenames {
         let full_rel_filepath = format!("{}/{}", parent_path.original, filename);
-        let self_config = read_config_file(&full_rel_filepath);
+        let self_config = read_config_file(&full_rel_filepath)?;
         let self_path = Path::from_str(&full_rel_filepath);
         let self_parent_path = self_path.parent().unwrap(); // All config files must be in directory
 
-        let mut child_config_list = read_all_config_files(&self_config.config_files, &self_parent_path);
+        let mut child_config_list = read_all_config_files(&self_config.config_files, &self_parent_path)?;
         
         config_list.append(&mut child_config_list);
         config_list.push(ConfigWithPath {
@@ -134,22 +230,41 @@ fn read_all_config_files(config_filenames: &Vec<String>, parent_path: &Path) ->
         });
     }
 
-    config_list
+    Ok(config_list)
 }
 
-fn read_config_file(filename: &str) -> Config {
+fn read_config_file(filename: &str) -> Result<Config, String> {
     let config_file = OpenOptions::new()
         .read(true)
         .open(filename);
-    let mut config_file = expect_pretty(config_file, &format!("Critical Error: Could not open config file \"{}\"", filename));
+    let mut config_file = config_file.map_err(|_| format!("Critical Error: Could not open config file \"{}\"", filename))?;
 
     let mut config_json = String::new();
-    expect_pretty(config_file.read_to_string(&mut config_json), &format!("Critical Error: Could not read config file \"{}\"", filename));
+    config_file.read_to_string(&mut config_json).map_err(|_| format!("Critical Error: Could not read config file \"{}\"", filename))?;
     
     let config: Result<Config, _> = serde_json::from_str(&config_json);
-    let config = expect_pretty(config, &format!("Critical Error: Invalid config file \"{}\"", filename));
+    let mut config = config.map_err(|_| format!("Critical Error: Invalid config file \"{}\"", filename))?;
+    lowercase_domains(&mut config);
+
+    Ok(config)
+}
+
+// Hosts of requests are compared in lowercase, so the configured domains have to be as well
+fn lowercase_domains(config: &mut Config) {
+    fn lowercase(domain: &mut Option<String>) {
+        if let Some(val) = domain {
+            *val = val.to_lowercase();
+        }
+    }
 
-    config
+    lowercase(&mut config.domain);
+    config.dynamic.iter_mut().for_each(|obj| lowercase(&mut obj.domain));
+    config.link.iter_mut().for_each(|obj| lowercase(&mut obj.domain));
+    config.redirect.iter_mut().for_each(|obj| lowercase(&mut obj.domain));
+    config.gone.iter_mut().for_each(|obj| lowercase(&mut obj.domain));
+    config.proxy.iter_mut().for_each(|obj| lowercase(&mut obj.domain));
+    config.upload.iter_mut().for_each(|obj| lowercase(&mut obj.domain));
+    config.access.iter_mut().for_each(|obj| lowercase(&mut obj.domain));
 }
 
 fn get_root_node(settings: &ServerSettings) -> UrlNode {
@@ -177,6 +292,9 @@ fn create_tree(config_list: &Vec<ConfigWithPath>, root_node: &mut UrlNode, setti
             None => String::from(&settings.domain)
         };
 
+        // Certificates (inherited from the config files above if not set)
+        let requires_certificate = get_requires_certificate(config_list, &config_dir_path, root_depth);
+
         // Preload
         let preload = match &config.config.default_preload {
             Some(val) => *val,
@@ -206,7 +324,8 @@ fn create_tree(config_list: &Vec<ConfigWithPath>, root_node: &mut UrlNode, setti
                 let file_data = NormalFile {
                     domain: domain.clone(),
                     path: path.clone(),
-                    mime_type: get_mime_type(&path)
+                    mime_type: get_mime_type(&path),
+                    requires_certificate
                 };
                 root_node.add_file_path(
                     &file_path,
@@ -253,7 +372,8 @@ fn create_tree(config_list: &Vec<ConfigWithPath>, root_node: &mut UrlNode, setti
                 let file_data = NormalFile {
                     domain: domain.clone(),
                     path: path.clone(),
-                    mime_type: get_mime_type(&path)
+                    mime_type: get_mime_type(&path),
+                    requires_certificate
                 };
                 root_node.add_file_path(
                     &file_path,
@@ -282,6 +402,11 @@ fn create_tree(config_list: &Vec<ConfigWithPath>, root_node: &mut UrlNode, setti
                 );
             }
 
+            // Use config certificate requirement if not defined
+            if let None = link_obj.requires_certificate {
+                link_obj.requires_certificate = Some(requires_certificate);
+            }
+
             // Possibly reset preload
             let preload = match &link_obj.preload {
                 Some(val) => *val,
@@ -314,6 +439,87 @@ fn create_tree(config_list: &Vec<ConfigWithPath>, root_node: &mut UrlNode, setti
             );
         }
 
+        // Handle redirects:
+        for redirect_obj in &config.config.redirect {
+            let mut redirect_obj = redirect_obj.clone();
+
+            // Set domain to config domain if not defined
+            if let None = redirect_obj.domain {
+                redirect_obj.domain = Some(domain.clone());
+            }
+
+            // Get link path relative to root
+            let link_path;
+            if config_dir_path.is_root() {
+                link_path = Path::from_str(&redirect_obj.link_path);
+            }
+            else {
+                link_path = Path::from_parent(&config_dir_path, &Path::from_str(&redirect_obj.link_path));
+            }
+
+            // Relative targets are turned into absolute, percent-encoded url paths, full urls are kept as they are
+            if !redirect_obj.target.contains("://") {
+                let target_path;
+                if config_dir_path.is_root() {
+                    target_path = Path::from_str(&redirect_obj.target);
+                }
+                else {
+                    target_path = Path::from_parent(&config_dir_path, &Path::from_str(&redirect_obj.target));
+                }
+
+                redirect_obj.target = protocol::encode_path(&format!("/{}", target_path.components.join("/")));
+            }
+            else if !redirect_obj.target.starts_with("gemini://") {
+                if settings.never_exit {
+                    log(&format!(
+                        "Warning: A redirect in the {} config file points to a url that is not a gemini url", &real_config_dir_path.original
+                    ));
+                }
+                else {
+                    panic!("Error: A redirect in the {} config file points to a url that is not a gemini url", &real_config_dir_path.original);
+                }
+            }
+
+            // Add path
+            root_node.add_file_path(
+                &link_path,
+                FileData::from_file_type(
+                    FileType::Redirect(redirect_obj),
+                    settings.never_exit,
+                    false // This parameter does nothing here
+                )
+            );
+        }
+
+        // Handle removed resources:
+        for gone_obj in &config.config.gone {
+            let mut gone_obj = gone_obj.clone();
+
+            // Set domain to config domain if not defined
+            if let None = gone_obj.domain {
+                gone_obj.domain = Some(domain.clone());
+            }
+
+            // Get link path relative to root
+            let link_path;
+            if config_dir_path.is_root() {
+                link_path = Path::from_str(&gone_obj.link_path);
+            }
+            else {
+                link_path = Path::from_parent(&config_dir_path, &Path::from_str(&gone_obj.link_path));
+            }
+
+            // Add path
+            root_node.add_file_path(
+                &link_path,
+                FileData::from_file_type(
+                    FileType::Gone(gone_obj),
+                    settings.never_exit,
+                    false // This parameter does nothing here
+                )
+            );
+        }
+
         // Handle dynamic content:
         for dynamic_obj in &config.config.dynamic {
             let mut dynamic_obj = dynamic_obj.clone();
@@ -328,6 +534,13 @@ fn create_tree(config_list: &Vec<ConfigWithPath>, root_node: &mut UrlNode, setti
             if let None = dynamic_obj.gen_time {
                 dynamic_obj.gen_time = Some(settings.max_dynamic_gen_time);
             }
+            // Use default resource limits and user if not defined
+            dynamic_obj.max_output_size = dynamic_obj.max_output_size.or(settings.max_dynamic_output_size);
+            dynamic_obj.max_memory = dynamic_obj.max_memory.or(settings.max_dynamic_memory);
+            dynamic_obj.max_cpu_time = dynamic_obj.max_cpu_time.or(settings.max_dynamic_cpu_time);
+            dynamic_obj.max_open_files = dynamic_obj.max_open_files.or(settings.max_dynamic_open_files);
+            dynamic_obj.uid = dynamic_obj.uid.or(settings.dynamic_uid);
+            dynamic_obj.gid = dynamic_obj.gid.or(settings.dynamic_gid);
             // Use config domain if not defined
             if let None = dynamic_obj.domain {
                 dynamic_obj.domain = Some(domain.clone());
@@ -367,6 +580,229 @@ fn create_tree(config_list: &Vec<ConfigWithPath>, root_node: &mut UrlNode, setti
     }
 }
 
+fn get_proxies(config_list: &Vec<ConfigWithPath>, settings: &ServerSettings) -> Vec<ProxyObject> {
+    let mut proxies = Vec::new();
+
+    for (_, _, proxy_list) in get_path_objects(config_list, settings, |config| &config.proxy, |obj| (&mut obj.domain, &mut obj.link_path)) {
+        for mut proxy_obj in proxy_list {
+            // Use default gen time if not defined
+            if let None = proxy_obj.timeout {
+                proxy_obj.timeout = Some(settings.max_dynamic_gen_time);
+            }
+
+            proxies.push(proxy_obj);
+        }
+    }
+
+    proxies
+}
+
+fn get_uploads(config_list: &Vec<ConfigWithPath>, settings: &ServerSettings) -> Vec<UploadObject> {
+    let root_path = Path::from_str(&settings.root);
+    let mut uploads = Vec::new();
+
+    for (config, config_dir_path, upload_list) in get_path_objects(config_list, settings, |config| &config.upload, |obj| (&mut obj.domain, &mut obj.link_path)) {
+        for mut upload_obj in upload_list {
+            // Use default upload size if not defined
+            if let None = upload_obj.max_size {
+                upload_obj.max_size = Some(settings.max_upload_size);
+            }
+            // Fingerprints are compared in uppercase
+            upload_obj.certificates = upload_obj.certificates.iter()
+                .map(|val| normalize_fingerprint(val))
+                .collect();
+
+            // Get directory including root
+            if config_dir_path.is_root() {
+                upload_obj.directory = upload_obj.directory.map(|dir| {
+                    Path::from_parent(&root_path, &Path::from_str(&dir)).original
+                });
+            }
+            else {
+                upload_obj.directory = upload_obj.directory.map(|dir| {
+                    Path::from_parent(&Path::from_parent(&root_path, &config_dir_path), &Path::from_str(&dir)).original
+                });
+            }
+
+            // Without a token or certificates nobody could be authorized, so such objects accept no uploads
+            if upload_obj.token.is_none() && upload_obj.certificates.len() == 0 {
+                if settings.never_exit {
+                    log(&format!(
+                        "Warning: An upload object in the {} config file has neither a token nor certificates and accepts no uploads", &config.path.original
+                    ));
+                }
+                else {
+                    panic!("Error: An upload object in the {} config file has neither a token nor certificates", &config.path.original);
+                }
+            }
+
+            uploads.push(upload_obj);
+        }
+    }
+
+    uploads
+}
+
+fn get_access(config_list: &Vec<ConfigWithPath>, settings: &ServerSettings) -> Vec<AccessObject> {
+    let root_depth = Path::from_str(&settings.root).depth();
+    let mut access = Vec::new();
+
+    for (config, config_dir_path, access_list) in get_path_objects(config_list, settings, |config| &config.access, |obj| (&mut obj.domain, &mut obj.link_path)) {
+        let groups = get_certificate_groups(config_list, &config_dir_path, root_depth);
+
+        for mut access_obj in access_list {
+            // Replace group names with the fingerprints of their members
+            let mut fingerprints: Vec<String> = Vec::new();
+            for entry in &access_obj.certificates {
+                if let Some(members) = groups.get(entry) {
+                    fingerprints.extend(members.iter().cloned());
+                    continue;
+                }
+
+                let fingerprint = normalize_fingerprint(entry);
+                if fingerprint.len() == 64 && fingerprint.chars().all(|c| c.is_ascii_hexdigit()) {
+                    fingerprints.push(fingerprint);
+                }
+                else if settings.never_exit {
+                    log(&format!(
+                        "Warning: An access object in the {} config file uses the unknown certificate group \"{}\"", &config.path.original, entry
+                    ));
+                }
+                else {
+                    panic!("Error: An access object in the {} config file uses the unknown certificate group \"{}\"", &config.path.original, entry);
+                }
+            }
+            access_obj.certificates = fingerprints;
+
+            if access_obj.certificates.len() == 0 {
+                log(&format!(
+                    "Warning: An access object in the {} config file does not allow any certificate", &config.path.original
+                ));
+            }
+
+            access.push(access_obj);
+        }
+    }
+
+    access
+}
+
+// Returns the objects of each config file with the config file and its directory relative to root.
+// Their domain defaults to the one of the config file and their link path is made relative to root
+fn get_path_objects<'a, T: Clone>(
+    config_list: &'a Vec<ConfigWithPath>,
+    settings: &ServerSettings,
+    get_objects: impl Fn(&Config) -> &Vec<T>,
+    get_location: impl Fn(&mut T) -> (&mut Option<String>, &mut String)
+) -> Vec<(&'a ConfigWithPath, Path, Vec<T>)> {
+    let root_depth = Path::from_str(&settings.root).depth();
+    let mut path_objects = Vec::new();
+
+    for config in config_list {
+        let config_dir_path = config.path.parent().unwrap().skip_components(root_depth); // All config files have a parent folder
+        let mut objects = get_objects(&config.config).clone();
+
+        for obj in &mut objects {
+            let (domain, link_path) = get_location(obj);
+
+            // Use config domain if not defined
+            if let None = domain {
+                *domain = Some(match &config.config.domain {
+                    Some(val) => val.clone(),
+                    None => settings.domain.clone()
+                });
+            }
+
+            // Get link path relative to root
+            if !config_dir_path.is_root() {
+                *link_path = Path::from_parent(&config_dir_path, &Path::from_str(link_path))
+                    .components
+                    .join("/");
+            }
+        }
+
+        path_objects.push((config, config_dir_path, objects));
+    }
+
+    path_objects
+}
+
+// Returns the object with the longest link path that contains the requested path
+pub fn find_path_object<'a, T>(
+    objects: &'a [T],
+    host: &str,
+    path: &str,
+    get_location: impl Fn(&T) -> (&Option<String>, &String)
+) -> Option<&'a T> {
+    let path = Path::from_str(path);
+    let mut found: Option<(&T, usize)> = None;
+
+    for obj in objects {
+        let (domain, link_path) = get_location(obj);
+        if domain.as_ref().unwrap() != host { // Domain is always set at this point
+            continue;
+        }
+
+        let link_path = Path::from_str(link_path);
+        if !path.starts_with(&link_path) {
+            continue;
+        }
+
+        match found {
+            Some((_, depth)) if depth >= link_path.depth() => (),
+            _ => found = Some((obj, link_path.depth()))
+        }
+    }
+
+    found.map(|(obj, _)| obj)
+}
+
+// Returns the groups declared in the config files on the level of the directory and above,
+// groups on lower levels replace groups with the same name on higher levels
+fn get_certificate_groups(config_list: &Vec<ConfigWithPath>, dir_path: &Path, root_depth: usize) -> HashMap<String, Vec<String>> {
+    let mut groups = HashMap::new();
+
+    for config in config_list { // Sorted from lowest depth to highest
+        let config_dir_path = config.path.parent().unwrap().skip_components(root_depth);
+        if !dir_path.starts_with(&config_dir_path) {
+            continue;
+        }
+
+        for group in &config.config.certificate_groups {
+            let members = group.certificates.iter()
+                .map(|val| normalize_fingerprint(val))
+                .collect();
+            groups.insert(group.name.clone(), members);
+        }
+    }
+
+    groups
+}
+
+// Returns the certificate requirement of the lowest config file on the level of the directory
+// or above which sets one, false if none do
+fn get_requires_certificate(config_list: &Vec<ConfigWithPath>, dir_path: &Path, root_depth: usize) -> bool {
+    let mut requires_certificate = false;
+
+    for config in config_list { // Sorted from lowest depth to highest
+        let config_dir_path = config.path.parent().unwrap().skip_components(root_depth);
+        if !dir_path.starts_with(&config_dir_path) {
+            continue;
+        }
+
+        if let Some(val) = config.config.requires_certificate {
+            requires_certificate = val;
+        }
+    }
+
+    requires_certificate
+}
+
+// Fingerprints may be written with colons and in lowercase, but are compared without both
+fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint.replace(":", "").to_uppercase()
+}
+
 fn seperate_roots(node: &UrlNode, path: Path, nodes_with_path: &mut HashMap<String, Vec<(Path, UrlNode)>>) {
     for child in &node.children {
         // Get path relative to root
@@ -1,6 +1,7 @@
 use std::default::Default;
 use std::fmt::{ Display, Formatter };
 use std::fs;
+use std::path::PathBuf;
 use std::hash::Hash;
 use serde::{ Serialize, Deserialize };
 use crate::log;
@@ -16,13 +17,28 @@ pub struct UrlNode {
 pub struct UrlTree {
     pub settings: ServerSettings,
     pub roots: Vec<UrlNode>,
+    pub proxies: Vec<ProxyObject>, // Proxies match all paths below their link path, so they are kept seperate
+    pub uploads: Vec<UploadObject>, // Same as above
+    pub access: Vec<AccessObject>, // Same as above
+    pub config_files: Vec<PathBuf> // Canonical paths of the settings file and all config files
 }
 
 impl UrlTree {
-    pub fn new(settings: ServerSettings, roots: Vec<UrlNode>) -> Self {
+    pub fn new(
+        settings: ServerSettings,
+        roots: Vec<UrlNode>,
+        proxies: Vec<ProxyObject>,
+        uploads: Vec<UploadObject>,
+        access: Vec<AccessObject>,
+        config_files: Vec<PathBuf>
+    ) -> Self {
         UrlTree {
             settings,
-            roots
+            roots,
+            proxies,
+            uploads,
+            access,
+            config_files
         }
     }
 }
@@ -40,6 +56,14 @@ impl FileData {
                 meta_data: FileType::Dynamic(val.clone()),
                 binary_data: None
             },
+            FileType::Redirect(val) => return FileData {
+                meta_data: FileType::Redirect(val.clone()),
+                binary_data: None
+            },
+            FileType::Gone(val) => return FileData {
+                meta_data: FileType::Gone(val.clone()),
+                binary_data: None
+            },
             FileType::Link(val) => &val.file_path,
             FileType::Normal(val) => &val.path.original
         };
@@ -79,7 +103,9 @@ impl FileData {
 pub enum FileType {
     Dynamic(DynamicObject),
     Link(LinkObject),
-    Normal(NormalFile)
+    Normal(NormalFile),
+    Redirect(RedirectObject),
+    Gone(GoneObject)
 }
 
 impl FileType {
@@ -87,7 +113,17 @@ impl FileType {
         match self {
             FileType::Dynamic(val) => &val.mime_type.as_ref().unwrap(), // Mime-type has been initialized at this point
             FileType::Link(val) => &val.mime_type.as_ref().unwrap(), //Same as above
-            FileType::Normal(val) => &val.mime_type
+            FileType::Normal(val) => &val.mime_type,
+            FileType::Redirect(_) | FileType::Gone(_) => "" // Redirects and tombstones have no content
+        }
+    }
+
+    // Dynamic objects ask for certificates themselves with "takes_certificate"
+    pub fn requires_certificate(&self) -> bool {
+        match self {
+            FileType::Link(val) => val.requires_certificate.unwrap_or(false), // Initialized at this point
+            FileType::Normal(val) => val.requires_certificate,
+            _ => false
         }
     }
 }
@@ -96,7 +132,8 @@ impl FileType {
 pub struct NormalFile {
     pub domain: String,
     pub path: Path,
-    pub mime_type: String
+    pub mime_type: String,
+    pub requires_certificate: bool
 }
 
 impl UrlNode {
@@ -238,7 +275,9 @@ impl UrlNode {
         match &self.data.as_ref().unwrap().meta_data {
             FileType::Normal(val) => &val.domain,
             FileType::Link(val) => val.domain.as_ref().unwrap(),
-            FileType::Dynamic(val) => val.domain.as_ref().unwrap()
+            FileType::Dynamic(val) => val.domain.as_ref().unwrap(),
+            FileType::Redirect(val) => val.domain.as_ref().unwrap(),
+            FileType::Gone(val) => val.domain.as_ref().unwrap()
         }
     }
 
@@ -318,7 +357,9 @@ impl UrlNode {
         let text_path = match &self.data.as_ref().unwrap().meta_data {
             FileType::Normal(val) => &val.path.original,
             FileType::Link(val) => &val.file_path,
-            FileType::Dynamic(_) => "\"dynamic\""
+            FileType::Dynamic(_) => "\"dynamic\"",
+            FileType::Redirect(val) => &val.target,
+            FileType::Gone(_) => "\"gone\""
         };
 
         String::from(text_path)
@@ -398,6 +439,10 @@ impl Path {
         self.components[self.components.len() - 1].clone()
     }
 
+    pub fn starts_with(&self, other: &Self) -> bool {
+        self.components.starts_with(&other.components)
+    }
+
     pub fn is_root(&self) -> bool {
         self.components.len() == 0
     }
@@ -414,8 +459,21 @@ pub struct ServerSettings {
     pub root: String,
     pub tls_profile: String,
     pub profile_password: String,
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    pub tls_chain: Option<String>,
+    pub certificates: Vec<DomainCertificate>,
+    pub certificate_warning_days: u32,
+    pub renew_self_signed: bool,
+    pub validate_client_certificates: bool,
     pub config_files: Vec<String>,
     pub max_dynamic_gen_time: u64,
+    pub max_dynamic_output_size: Option<u64>,
+    pub max_dynamic_memory: Option<u64>,
+    pub max_dynamic_cpu_time: Option<u64>,
+    pub max_dynamic_open_files: Option<u64>,
+    pub dynamic_uid: Option<u32>,
+    pub dynamic_gid: Option<u32>,
     pub cache_time: u64,
     pub default_preload: bool,
     pub never_exit: bool,
@@ -424,8 +482,16 @@ pub struct ServerSettings {
     pub default_lang: Option<String>,
     pub default_charset: Option<String>,
     pub homepage: Option<String>,
-    pub ipv4: bool,
-    pub ipv6: bool
+    pub listen: Vec<String>,
+    pub rate_limit: Option<RateLimit>,
+    pub max_upload_size: u64,
+    pub workers: usize,
+    pub queue_length: usize,
+    pub handshake_timeout: u64,
+    pub read_timeout: u64,
+    pub write_timeout: u64,
+    pub shutdown_grace_period: u64,
+    pub watch_interval: u64
 }
 
 impl Default for ServerSettings {
@@ -435,10 +501,23 @@ impl Default for ServerSettings {
             root: String::from("root"),
             tls_profile: String::from("profile.pfx"),
             profile_password: String::from("password"),
+            tls_cert: None,
+            tls_key: None,
+            tls_chain: None,
+            certificates: Vec::new(),
+            certificate_warning_days: 14,
+            renew_self_signed: false,
+            validate_client_certificates: false,
             config_files: vec![
                 String::from("config.json")
             ],
             max_dynamic_gen_time: 10,
+            max_dynamic_output_size: None,
+            max_dynamic_memory: None,
+            max_dynamic_cpu_time: None,
+            max_dynamic_open_files: None,
+            dynamic_uid: None,
+            dynamic_gid: None,
             cache_time: 300,
             default_preload: true,
             never_exit: false,
@@ -447,12 +526,47 @@ impl Default for ServerSettings {
             default_lang: None,
             default_charset: None,
             homepage: None,
-            ipv4: true,
-            ipv6: false
+            listen: vec![
+                String::from("0.0.0.0:1965")
+            ],
+            rate_limit: None,
+            max_upload_size: 1048576,
+            workers: 16,
+            queue_length: 32,
+            handshake_timeout: 10,
+            read_timeout: 10,
+            write_timeout: 30,
+            shutdown_grace_period: 10,
+            watch_interval: 0
         }
     }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize, Hash)]
+pub struct DomainCertificate {
+    pub domain: String,
+    #[serde(default = "Option::default")]
+    pub tls_profile: Option<String>,
+    #[serde(default = "String::new")]
+    pub profile_password: String,
+    #[serde(default = "Option::default")]
+    pub tls_cert: Option<String>,
+    #[serde(default = "Option::default")]
+    pub tls_key: Option<String>,
+    #[serde(default = "Option::default")]
+    pub tls_chain: Option<String>
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Hash)]
+pub struct RateLimit {
+    pub requests: u32, // Requests allowed per window
+    pub window: u64, // Seconds
+    #[serde(default = "Option::default")]
+    pub burst: Option<u32>, // Requests allowed at once, defaults to requests
+    #[serde(default = "bool::default")]
+    pub by_certificate: bool // Limit clients by certificate fingerprint as well if one is given
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, Hash)]
 pub struct Config {
     pub domain: Option<String>,
@@ -466,8 +580,22 @@ pub struct Config {
     #[serde(default = "Vec::new")]
     pub link: Vec<LinkObject>,
     #[serde(default = "Vec::new")]
+    pub redirect: Vec<RedirectObject>,
+    #[serde(default = "Vec::new")]
+    pub gone: Vec<GoneObject>,
+    #[serde(default = "Vec::new")]
+    pub proxy: Vec<ProxyObject>,
+    #[serde(default = "Vec::new")]
+    pub upload: Vec<UploadObject>,
+    #[serde(default = "Vec::new")]
+    pub access: Vec<AccessObject>,
+    #[serde(default = "Vec::new")]
+    pub certificate_groups: Vec<CertificateGroup>,
+    #[serde(default = "Vec::new")]
     pub config_files: Vec<String>,
-    pub default_preload: Option<bool>
+    pub default_preload: Option<bool>,
+    #[serde(default = "Option::default")]
+    pub requires_certificate: Option<bool>
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Hash)]
@@ -482,9 +610,25 @@ pub struct DynamicObject {
     pub query: Option<Query>,
     #[serde(default = "bool::default")]
     pub takes_certificate: bool,
+    #[serde(default = "CgiMode::default")]
+    pub cgi_mode: CgiMode,
+    #[serde(default = "bool::default")]
+    pub log_stderr_on_success: bool,
     pub cache: bool,
     pub mime_type: Option<String>,
     pub gen_time: Option<u64>,
+    #[serde(default = "Option::default")]
+    pub max_output_size: Option<u64>, // Bytes
+    #[serde(default = "Option::default")]
+    pub max_memory: Option<u64>, // Bytes of address space
+    #[serde(default = "Option::default")]
+    pub max_cpu_time: Option<u64>, // Seconds
+    #[serde(default = "Option::default")]
+    pub max_open_files: Option<u64>,
+    #[serde(default = "Option::default")]
+    pub uid: Option<u32>,
+    #[serde(default = "Option::default")]
+    pub gid: Option<u32>,
     pub domain: Option<String>
 }
 
@@ -494,7 +638,78 @@ pub struct LinkObject {
     pub file_path: String,
     pub link_path: String,
     pub mime_type: Option<String>,
-    pub preload: Option<bool>
+    pub preload: Option<bool>,
+    #[serde(default = "Option::default")]
+    pub requires_certificate: Option<bool>
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Hash)]
+pub struct RedirectObject {
+    pub domain: Option<String>,
+    pub link_path: String,
+    pub target: String, // Relative path or absolute gemini url
+    #[serde(default = "bool::default")]
+    pub permanent: bool
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Hash)]
+pub struct GoneObject {
+    pub domain: Option<String>,
+    pub link_path: String,
+    #[serde(default = "Option::default")]
+    pub message: Option<String> // Custom meta for the response
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Hash)]
+pub struct ProxyObject {
+    pub domain: Option<String>,
+    pub link_path: String, // Relative, empty for the entire domain
+    pub host: String, // Upstream server
+    pub port: Option<u16>,
+    #[serde(default = "Option::default")]
+    pub path: Option<String>, // Upstream path that replaces the link path
+    #[serde(default = "Option::default")]
+    pub tls_profile: Option<String>, // Certificate presented to the upstream server
+    #[serde(default = "Option::default")]
+    pub profile_password: Option<String>,
+    #[serde(default = "Option::default")]
+    pub timeout: Option<u64>
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Hash)]
+pub struct UploadObject {
+    pub domain: Option<String>,
+    pub link_path: String, // Relative, uploads are allowed for all paths below it
+    #[serde(default = "Option::default")]
+    pub directory: Option<String>, // Relative, if null uploads are passed to dynamic objects
+    pub max_size: Option<u64>,
+    #[serde(default = "Option::default")]
+    pub token: Option<String>,
+    #[serde(default = "Vec::new")]
+    pub certificates: Vec<String> // Fingerprints allowed to upload
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Hash)]
+pub struct AccessObject {
+    pub domain: Option<String>,
+    pub link_path: String, // Relative, access is restricted for all paths below it
+    pub certificates: Vec<String> // Fingerprints or names of certificate groups, only fingerprints after the tree is built
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Hash)]
+pub struct CertificateGroup {
+    pub name: String,
+    pub certificates: Vec<String> // Fingerprints
+}
+
+// How request information is passed to a dynamic object
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum CgiMode {
+    #[default]
+    Legacy, // Command line arguments
+    Cgi, // Standard CGI environment variables
+    Stdout // Standard CGI environment variables, the response is read from stdout
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Hash)]
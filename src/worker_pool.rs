@@ -0,0 +1,54 @@
+use std::sync::{ Arc, Mutex };
+use std::sync::mpsc::{ self, SyncSender, TrySendError };
+use std::panic::{ self, AssertUnwindSafe };
+use std::thread;
+
+// A fixed amount of worker threads which handle items from a bounded queue
+pub struct WorkerPool<T> {
+    sender: SyncSender<T>
+}
+
+impl<T: Send + 'static> WorkerPool<T> {
+    pub fn new<F>(workers: usize, queue_length: usize, handler: F) -> Self
+    where F: Fn(T) + Send + Sync + 'static {
+        let (sender, receiver) = mpsc::sync_channel::<T>(queue_length);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let handler = Arc::new(handler);
+
+        for _ in 0..workers.max(1) {
+            let receiver = receiver.clone();
+            let handler = handler.clone();
+
+            thread::spawn(move || {
+                loop {
+                    let item = match receiver.lock() {
+                        Ok(val) => val.recv(),
+                        Err(_) => return
+                    };
+                    let item = match item {
+                        Ok(val) => val,
+                        Err(_) => return // Pool has been dropped
+                    };
+
+                    // A panic while handling one item should not take the worker down with it
+                    match panic::catch_unwind(AssertUnwindSafe(|| handler(item))) {
+                        Ok(_) => (),
+                        Err(_) => ()
+                    };
+                }
+            });
+        }
+
+        WorkerPool {
+            sender
+        }
+    }
+
+    // Returns the item if the queue is full
+    pub fn queue(&self, item: T) -> std::result::Result<(), T> {
+        match self.sender.try_send(item) {
+            Ok(_) => Ok(()),
+            Err(TrySendError::Full(item)) | Err(TrySendError::Disconnected(item)) => Err(item)
+        }
+    }
+}